@@ -5,7 +5,7 @@ use bitcask::{Db, OnDisk, ToDisk};
 use libfuzzer_sys::fuzz_target;
 
 fuzz_target!(|methods: Vec<Op<String, u64>>| {
-    let mut db = OnDisk::open("test").unwrap();
+    let mut db: OnDisk<String, u64> = OnDisk::open("test").unwrap();
     for method in methods {
         eval_op(&mut db, method);
     }
@@ -1,9 +1,11 @@
-#![feature(let_chains)]
-#![feature(fs_try_exists)]
-
-use crc::{self, Crc, CRC_32_CKSUM};
+use crc::{self, Crc, CRC_32_CKSUM, CRC_32_ISCSI, CRC_32_ISO_HDLC};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::btree_map;
 use std::collections::BTreeSet;
 use std::fmt::Debug;
 use std::fs;
@@ -16,464 +18,7932 @@ use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
 use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{collections::BTreeMap, fs::File};
 
-use anyhow::Result;
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+
+use anyhow::{anyhow, bail, Result};
 
 pub trait Db<K, V> {
-    fn get(&self, key: &K) -> Option<V>;
-    fn put(&mut self, key: K, value: V) -> Result<V>;
+    fn get(&self, key: &K) -> Result<Option<V>>;
+    /// Writes `value` under `key`, returning the value it replaced, like
+    /// `HashMap::insert`. An old value that can't be decoded, or whose record
+    /// fails its checksum, comes back as `None` rather than failing the write;
+    /// other errors reading it, like I/O errors, are returned.
+    /// The write may sit in the OS's buffers, and be lost in a crash, until
+    /// `ToDisk::sync` returns.
+    fn put(&mut self, key: K, value: V) -> Result<Option<V>>;
     fn delete(&mut self, key: &K) -> Result<()>;
-    fn keys(&mut self) -> Vec<&K>;
-    fn values(&mut self) -> Vec<V>;
-    fn items(&mut self) -> Vec<(&K, V)>;
+    /// Deletes every key, leaving the database as if freshly opened on an
+    /// empty directory. Settings are kept.
+    fn clear(&mut self) -> Result<()>;
+    fn keys(&mut self) -> Result<Vec<&K>>;
+    fn values(&mut self) -> Result<Vec<V>>;
+    fn items(&mut self) -> Result<Vec<(&K, V)>>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool;
+    /// Returns whether `key` is live, from the index alone without reading
+    /// its value. Fallible because an index kept partly on disk, like that of
+    /// `OnDisk::set_key_dir_limit`, may have to be read to answer.
+    fn contains_key(&self, key: &K) -> Result<bool>;
 }
 
-pub trait ToDisk<K, V>: Db<K, V>
+pub trait ToDisk<K, V>: Db<K, V> + Sized
 where
     K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
     V: Serialize + DeserializeOwned,
 {
-    fn open(file_name: &str) -> Result<OnDisk<K, V>>;
+    fn open(file_name: &str) -> Result<Self>;
+    /// Opens the database called `name` in `dir`, creating the directory if
+    /// it doesn't exist. The same as `open` with `dir` joined to `name`.
+    fn open_in<P: AsRef<Path>>(dir: P, name: &str) -> Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let prefix = dir.join(name);
+        let prefix = prefix
+            .to_str()
+            .ok_or_else(|| anyhow!("database path {} is not UTF-8", prefix.display()))?;
+        Self::open(prefix)
+    }
+    /// Fsyncs every data file written to since the last sync, so the writes
+    /// so far survive a crash, and starts a new active file.
     fn sync(&mut self) -> Result<()>;
+    /// Like `sync`, but keeps appending to the same active file.
+    fn flush(&mut self) -> Result<()>;
+    /// Rewrites the live records into as few files as `max_file_size`
+    /// allows, numbered from 1, and starts a new active file after them.
     fn prune(&mut self) -> Result<()>;
+    fn prune_with_progress<F: FnMut(usize, usize)>(&mut self, cb: F) -> Result<()>;
+    fn replace_all<I: IntoIterator<Item = (K, V)>>(&mut self, items: I) -> Result<()>;
+    fn compact_deletes(&mut self) -> Result<()>;
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct Slot {
-    file_id: u64,
-    start: u64,
-    end: u64,
-}
+/// Version of the on-disk record layout.
+pub const FORMAT_VERSION: u32 = 7;
 
-pub struct OnDisk<K, V>
-where
-    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
-    V: Serialize + DeserializeOwned,
-{
-    key_dir: BTreeMap<K, (u64, usize, u64, Slot)>,
-    delete_map: BTreeMap<K, (u64, usize, u64, Slot)>,
-    prefix: String,
-    file_id: u64,
-    file_position: u64,
-    crc_hasher: Crc<u32>,
-    is_dirty: bool,
-    phantom_data: PhantomData<V>,
-    free_slots: BTreeMap<u64, Vec<Slot>>,
+/// Size of the record header: checksum, flags, key flags, sequence, timestamp,
+/// expiry, key length and value length.
+const HEADER_LEN: u64 = 4 + 1 + 4 + 8 + 8 + 8 + 8 + 8;
+
+/// Record flag marking a value stored compressed.
+const RECORD_COMPRESSED: u8 = 1;
+
+/// Record flag marking a delete. Tombstones have no value and stay on disk
+/// until a `prune`, so older records of the key are not read back as live.
+const RECORD_TOMBSTONE: u8 = 2;
+
+/// Record flag marking a value stored encrypted, behind its nonce.
+const RECORD_ENCRYPTED: u8 = 4;
+
+/// Record flag bits naming the `Checksum` the record was written with.
+const RECORD_CHECKSUM: u8 = 8 | 16;
+
+/// Record flag marking a key that shares the value of another record in the
+/// same file, as deduplication leaves them. The value is the sequence number
+/// of that record.
+const RECORD_ALIAS: u8 = 32;
+
+/// Record flag marking a write of a transaction, which only counts once the
+/// transaction's commit record is on disk too.
+const RECORD_BATCH: u8 = 64;
+
+/// Record flag marking the end of a committed transaction. It has no key, and
+/// its value is the sequence number of the transaction's first record, so
+/// every batch record from there up to the commit record's own is committed.
+const RECORD_COMMIT: u8 = 128;
+
+/// The CRC-32 variant records are checksummed with. Each record notes the
+/// one it was written with, so files keep reading back after a change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Checksum {
+    /// CRC-32/CKSUM, as the POSIX `cksum` tool computes it.
+    #[default]
+    Cksum,
+    /// CRC-32/ISCSI, the Castagnoli polynomial.
+    Iscsi,
+    /// CRC-32/ISO-HDLC, as zlib and Ethernet compute it.
+    IsoHdlc,
 }
 
-impl<K, V> OnDisk<K, V>
-where
-    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
-    V: Serialize + DeserializeOwned,
-{
-    fn get_file_by_id(&self, file_id: u64) -> Result<File> {
-        let file_name = format!("{}.{}.db", self.prefix, file_id);
-        let file = OpenOptions::new().read(true).write(true).open(file_name)?;
-        Ok(file)
+impl Checksum {
+    fn crc(self) -> &'static Crc<u32> {
+        static CKSUM: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+        static ISCSI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+        static ISO_HDLC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        match self {
+            Checksum::Cksum => &CKSUM,
+            Checksum::Iscsi => &ISCSI,
+            Checksum::IsoHdlc => &ISO_HDLC,
+        }
     }
 
-    fn get_tempfile_by_id(&self, file_id: u64) -> Result<File> {
-        let file_name = format!("{}.{}.temp.db", self.prefix, file_id);
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(file_name)?;
-        Ok(file)
+    fn flag(self) -> u8 {
+        match self {
+            Checksum::Cksum => 0,
+            Checksum::Iscsi => 8,
+            Checksum::IsoHdlc => 16,
+        }
     }
 
-    fn curr_file(&self) -> Result<File> {
-        let file_name = format!("{}.{}.db", self.prefix, self.file_id);
-        let file = OpenOptions::new().read(true).write(true).open(file_name)?;
-        Ok(file)
+    /// The checksum named by the flags of a record.
+    fn of_flags(flags: u8) -> Result<Checksum> {
+        match flags & RECORD_CHECKSUM {
+            0 => Ok(Checksum::Cksum),
+            8 => Ok(Checksum::Iscsi),
+            16 => Ok(Checksum::IsoHdlc),
+            _ => bail!("record names an unknown checksum"),
+        }
     }
+}
 
-    fn serialize_to_file(&self, key: &K, value: V, file: File) -> Result<(u64, usize, u64, Slot)> {
-        let serialized_key = bincode::serialize(&key)?;
-        let serialized_value = bincode::serialize(&value)?;
-        let serialized_key_len = bincode::serialize(&serialized_key.len())?;
-        let serialized_value_len = bincode::serialize(&serialized_value.len())?;
+/// Sequence number of filler records, which pad out the unused tail of a
+/// reused slot so data files can still be walked record by record.
+const FILLER_SEQ: u64 = u64::MAX;
 
-        let mut digest = self.crc_hasher.digest();
+/// Marker optionally written in front of records, so a scan that hits a
+/// corrupt record can find where the next one starts.
+const RECORD_SEPARATOR: [u8; 8] = [0xb1, 0x7c, 0xa5, 0x4b, 0x5e, 0x9a, 0x0d, 0xf3];
 
-        digest.update(&serialized_key_len);
-        digest.update(&serialized_value_len);
-        digest.update(&serialized_key);
-        digest.update(&serialized_value);
+/// Turns the keys and values of records into bytes and back. Chosen with the
+/// last type parameter of `OnDisk`; the files the database keeps for itself,
+/// like hint and spill files, stay bincode.
+pub trait Codec {
+    /// Recorded in the schema file.
+    const NAME: &'static str;
 
-        let checksum = digest.finalize();
-        let serialized_checksum = bincode::serialize(&checksum)?;
+    fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>>;
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
 
-        let mut writer = BufWriter::new(file);
-        writer.seek(SeekFrom::End(0))?;
-        let start_pos = writer.stream_position()?;
+/// The default codec.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
 
-        writer.write_all(&serialized_checksum)?;
-        writer.write_all(&serialized_key_len)?;
-        writer.write_all(&serialized_value_len)?;
-        writer.write_all(&serialized_key)?;
-        let value_pos = writer.stream_position()?;
-        writer.write_all(&serialized_value)?;
+impl Codec for BincodeCodec {
+    const NAME: &'static str = "bincode";
 
-        let end_pos = writer.stream_position()?;
-        let free_slot = Slot {
-            file_id: self.file_id,
-            start: start_pos,
-            end: end_pos,
-        };
-        Ok((self.file_id, serialized_value.len(), value_pos, free_slot))
+    fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes)?)
     }
 }
 
-impl<K, V> Drop for OnDisk<K, V>
-where
-    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
-    V: Serialize + DeserializeOwned,
-{
-    fn drop(&mut self) {
-        let _ = self.sync();
+/// Compresses the value bytes of records. Set with `OnDisk::set_compressor`.
+/// Each record notes whether its value is compressed, so a database can hold
+/// both kinds, but compressed values must be read with the compressor that
+/// wrote them.
+pub trait Compressor: Send + std::marker::Sync {
+    fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>>;
+    fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Deflate compression. Used by `set_compress_on_prune` when no compressor
+/// is set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Deflate;
+
+impl Compressor for Deflate {
+    fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes)?;
+        Ok(encoder.finish()?)
+    }
+
+    fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut decompressed = vec![];
+        DeflateDecoder::new(bytes).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
     }
 }
 
-impl<K, V> Db<K, V> for OnDisk<K, V>
-where
-    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
-    V: Serialize + DeserializeOwned,
-{
-    fn get(&self, key: &K) -> Option<V> {
-        if let Some((file_id, value_len, value_pos, _)) = self.key_dir.get(key) {
-            let mut reader = self
-                .get_file_by_id(*file_id)
-                .expect("failed to get file_id");
-            reader
-                .seek(SeekFrom::Start(*value_pos))
-                .expect("failed to seek");
+/// Encrypts the value bytes of records with a key of its own. Set with
+/// `OnDisk::set_cipher`. Each value is stored behind the nonce it was
+/// encrypted with, and the record checksum covers the ciphertext.
+#[cfg(feature = "encryption")]
+pub trait Cipher: Send + std::marker::Sync {
+    /// Length of the nonces `encrypt` returns.
+    fn nonce_len(&self) -> usize;
+    /// Encrypts `plaintext` under a fresh nonce, returning the nonce and the
+    /// ciphertext.
+    fn encrypt(&self, plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>)>;
+    fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
 
-            let mut value_buf = vec![0u8; *value_len];
-            reader
-                .read_exact(&mut value_buf)
-                .expect("failed to read value");
-            let value: V = bincode::deserialize(&value_buf).expect("Failed to deserialize value");
+/// Describes how a database's files were written, so tools can parse them
+/// without the program that wrote them. Stored as `{prefix}.schema`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schema {
+    pub key_type: String,
+    pub value_type: String,
+    pub codec: String,
+    pub format_version: u32,
+}
 
-            Some(value)
-        } else {
-            None
+impl Schema {
+    fn of<K, V, C: Codec>() -> Schema {
+        Schema {
+            key_type: std::any::type_name::<K>().to_string(),
+            value_type: std::any::type_name::<V>().to_string(),
+            codec: C::NAME.to_string(),
+            format_version: FORMAT_VERSION,
         }
     }
 
-    fn put(&mut self, key: K, value: V) -> Result<V> {
-        if self.key_dir.contains_key(&key) {
-            self.delete(&key)?;
+    /// Reads the schema file written for the database at `prefix`.
+    pub fn load(prefix: &str) -> Result<Schema> {
+        let contents = fs::read_to_string(format!("{}.schema", prefix))?;
+        let mut fields = BTreeMap::new();
+        for line in contents.lines() {
+            if let Some((name, value)) = line.split_once('=') {
+                fields.insert(name, value);
+            }
         }
-        let serialized_key = bincode::serialize(&key)?;
-        let serialized_value = bincode::serialize(&value)?;
-        let serialized_key_len = bincode::serialize(&serialized_key.len())?;
-        let serialized_value_len = bincode::serialize(&serialized_value.len())?;
-
-        let mut digest = self.crc_hasher.digest();
+        let field = |name| match fields.get(name) {
+            Some(value) => Ok(value.to_string()),
+            None => Err(anyhow!("schema for {} is missing {}", prefix, name)),
+        };
+        Ok(Schema {
+            key_type: field("key_type")?,
+            value_type: field("value_type")?,
+            codec: field("codec")?,
+            format_version: field("format_version")?.parse()?,
+        })
+    }
 
-        digest.update(&serialized_key_len);
-        digest.update(&serialized_value_len);
-        digest.update(&serialized_key);
-        digest.update(&serialized_value);
+    fn write(&self, prefix: &str) -> Result<()> {
+        let contents = format!(
+            "key_type={}\nvalue_type={}\ncodec={}\nformat_version={}\n",
+            self.key_type, self.value_type, self.codec, self.format_version
+        );
+        fs::write(format!("{}.schema", prefix), contents)?;
+        Ok(())
+    }
+}
 
-        let checksum = digest.finalize();
-        let serialized_checksum = bincode::serialize(&checksum)?;
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+struct Slot {
+    file_id: u64,
+    start: u64,
+    end: u64,
+}
 
-        let total_len = (serialized_key.len()
-            + serialized_value.len()
-            + serialized_key_len.len()
-            + serialized_value_len.len()
-            + serialized_checksum.len()) as u64;
+/// Space left behind by deleted and overwritten records, for new records to
+/// reuse. Kept by length to find a fit and by position so that neighbouring
+/// holes merge into one.
+#[derive(Debug, Default)]
+struct FreeSlots {
+    by_len: BTreeMap<u64, Vec<Slot>>,
+    by_pos: BTreeMap<(u64, u64), u64>,
+    /// Combined length of the free slots.
+    total_len: u64,
+}
 
-        let mut items = self.free_slots.range(total_len..);
+impl FreeSlots {
+    /// Adds `slot`, merged with any free slot right before or after it.
+    fn insert(&mut self, mut slot: Slot) {
+        let before = self
+            .by_pos
+            .range(..(slot.file_id, slot.start))
+            .next_back()
+            .filter(|((file_id, _), end)| *file_id == slot.file_id && **end == slot.start)
+            .map(|((_, start), _)| *start);
+        if let Some(start) = before {
+            self.remove(&Slot {
+                file_id: slot.file_id,
+                start,
+                end: slot.start,
+            });
+            slot.start = start;
+        }
+        if let Some(end) = self.by_pos.get(&(slot.file_id, slot.end)).copied() {
+            self.remove(&Slot {
+                file_id: slot.file_id,
+                start: slot.end,
+                end,
+            });
+            slot.end = end;
+        }
 
-        if let Some((length, free_slots)) = items.next()
-            && let Some(free_slot) = free_slots.last()
-        {
-            let file = self.get_file_by_id(free_slot.file_id)?;
-            let mut writer = BufWriter::new(file);
-            writer.seek(SeekFrom::Start(free_slot.start))?;
+        self.by_pos.insert((slot.file_id, slot.start), slot.end);
+        self.total_len += slot.end - slot.start;
+        self.by_len
+            .entry(slot.end - slot.start)
+            .or_default()
+            .push(slot);
+    }
 
-            writer.write_all(&serialized_checksum)?;
-            writer.write_all(&serialized_key_len)?;
-            writer.write_all(&serialized_value_len)?;
-            writer.write_all(&serialized_key)?;
-            let value_pos = writer.stream_position()?;
-            writer.write_all(&serialized_value)?;
+    fn remove(&mut self, slot: &Slot) {
+        let length = slot.end - slot.start;
+        if self.by_pos.remove(&(slot.file_id, slot.start)).is_some() {
+            self.total_len -= length;
+        }
+        if let Some(slots) = self.by_len.get_mut(&length) {
+            slots.retain(|free_slot| free_slot != slot);
+            if slots.is_empty() {
+                self.by_len.remove(&length);
+            }
+        }
+    }
 
-            let end_pos = writer.stream_position()?;
-            let free_slot = Slot {
-                file_id: free_slot.file_id,
-                start: free_slot.start,
-                end: end_pos,
-            };
-            self.key_dir.insert(
-                key,
-                (
-                    free_slot.file_id,
-                    serialized_value.len(),
-                    value_pos,
-                    free_slot.clone(),
-                ),
-            );
-            let mut free_slots = free_slots.clone();
-            free_slots.pop();
-            self.free_slots.insert(*length, free_slots);
-            self.file_position = end_pos;
-            self.is_dirty = true;
-        } else {
-            let file = self.curr_file()?;
-            let mut writer = BufWriter::new(file);
-            writer.seek(SeekFrom::Start(self.file_position))?;
+    /// Finds one of the shortest free slots at least `len` long that `fits`
+    /// accepts.
+    fn find(&self, len: u64, fits: impl Fn(&Slot) -> bool) -> Option<Slot> {
+        self.by_len
+            .range(len..)
+            .flat_map(|(_, slots)| slots.iter().rev())
+            .find(|slot| fits(slot))
+            .cloned()
+    }
 
-            writer.write_all(&serialized_checksum)?;
-            writer.write_all(&serialized_key_len)?;
-            writer.write_all(&serialized_value_len)?;
-            writer.write_all(&serialized_key)?;
-            let value_pos = writer.stream_position()?;
-            writer.write_all(&serialized_value)?;
+    fn iter(&self) -> impl Iterator<Item = &Slot> {
+        self.by_len.values().flatten()
+    }
 
-            let end_pos = writer.stream_position()?;
-            let free_slot = Slot {
-                file_id: self.file_id,
-                start: self.file_position,
-                end: end_pos,
-            };
-            self.key_dir.insert(
-                key,
-                (
-                    self.file_id,
-                    serialized_value.len(),
-                    value_pos,
-                    free_slot.clone(),
-                ),
-            );
-            self.file_position = end_pos;
-            self.is_dirty = true;
+    fn retain(&mut self, f: impl Fn(&Slot) -> bool) {
+        let removed: Vec<_> = self.iter().filter(|slot| !f(slot)).cloned().collect();
+        for slot in removed {
+            self.remove(&slot);
         }
+    }
+}
 
-        Ok(value)
+/// Spilled entries per block of the spill file; only the first key of each
+/// block stays in memory.
+const SPILL_BLOCK_LEN: usize = 64;
+
+/// How many imported records go by between checkpoint updates.
+const IMPORT_CHECKPOINT_INTERVAL: u64 = 1000;
+
+/// Where a key's latest record lives, kept in memory for every live key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    file_id: u64,
+    /// Length of the value bytes as stored, after any compression.
+    value_len: usize,
+    /// The record's header flags.
+    flags: u8,
+    /// Flags set by the caller through `put_with_flags`.
+    key_flags: u32,
+    /// Write order of the record, preserved when it is copied elsewhere.
+    seq: u64,
+    /// When the value was put, in milliseconds since the Unix epoch; also
+    /// preserved by copies.
+    timestamp: u64,
+    /// When the value stops being readable, in milliseconds since the Unix
+    /// epoch, or 0 if it never does.
+    expires_at: u64,
+    slot: Slot,
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
     }
+}
 
-    fn delete(&mut self, key: &K) -> Result<()> {
-        if let Some((file_id, value_len, value_pos, free_slot)) = self.key_dir.remove(key) {
-            let distance = free_slot.end - free_slot.start;
-            self.free_slots
-                .entry(distance)
-                .or_default()
-                .push(free_slot.clone());
-            self.delete_map
-                .insert(key.clone(), (file_id, value_len, value_pos, free_slot));
+/// The current time in milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Lays out a record as
+/// `checksum | flags | key_flags | seq | timestamp | expires_at | key_len |
+/// value_len | key | value`.
+/// The header fields are fixed-width big-endian regardless of the codec used
+/// for keys and values, so files parse the same on any machine.
+#[allow(clippy::too_many_arguments)]
+fn encode_record(
+    checksum: Checksum,
+    flags: u8,
+    key_flags: u32,
+    seq: u64,
+    timestamp: u64,
+    expires_at: u64,
+    serialized_key: &[u8],
+    serialized_value: &[u8],
+) -> Vec<u8> {
+    let mut record =
+        Vec::with_capacity(HEADER_LEN as usize + serialized_key.len() + serialized_value.len());
+    record.extend_from_slice(&[0; 4]);
+    record.push(flags & !RECORD_CHECKSUM | checksum.flag());
+    record.extend_from_slice(&key_flags.to_be_bytes());
+    record.extend_from_slice(&seq.to_be_bytes());
+    record.extend_from_slice(&timestamp.to_be_bytes());
+    record.extend_from_slice(&expires_at.to_be_bytes());
+    record.extend_from_slice(&(serialized_key.len() as u64).to_be_bytes());
+    record.extend_from_slice(&(serialized_value.len() as u64).to_be_bytes());
+    record.extend_from_slice(serialized_key);
+    record.extend_from_slice(serialized_value);
+
+    let checksum = checksum.crc().checksum(&record[4..]);
+    record[..4].copy_from_slice(&checksum.to_be_bytes());
+    record
+}
+
+/// What value bytes go through between being serialized and being stored.
+#[derive(Clone, Copy)]
+struct ValueFormat<'a> {
+    compressor: &'a dyn Compressor,
+    #[cfg(feature = "encryption")]
+    cipher: Option<&'a dyn Cipher>,
+}
+
+impl ValueFormat<'_> {
+    /// Turns serialized value bytes into the bytes to store, returned with
+    /// their record flags.
+    fn store(&self, bytes: Vec<u8>, compressed: bool) -> Result<(u8, Vec<u8>)> {
+        let (flags, bytes) = if compressed {
+            (RECORD_COMPRESSED, self.compressor.compress(&bytes)?)
+        } else {
+            (0, bytes)
+        };
+        #[cfg(feature = "encryption")]
+        let (flags, bytes) = match self.cipher {
+            Some(cipher) => {
+                let (nonce, ciphertext) = cipher.encrypt(&bytes)?;
+                if nonce.len() != cipher.nonce_len() {
+                    bail!("cipher returned a nonce of {} bytes", nonce.len());
+                }
+                (flags | RECORD_ENCRYPTED, [nonce, ciphertext].concat())
+            }
+            None => (flags, bytes),
+        };
+        Ok((flags, bytes))
+    }
+
+    /// Turns stored value bytes back into serialized ones.
+    fn load(&self, stored: Vec<u8>, flags: u8) -> Result<Vec<u8>> {
+        let bytes = if flags & RECORD_ENCRYPTED != 0 {
+            self.decrypt(&stored)?
+        } else {
+            stored
+        };
+        if flags & RECORD_COMPRESSED != 0 {
+            self.compressor.decompress(&bytes)
+        } else {
+            Ok(bytes)
         }
-        Ok(())
     }
 
-    fn keys(&mut self) -> Vec<&K> {
-        let keys: Vec<_> = self.key_dir.keys().collect();
-        keys
+    /// Brings stored value bytes into the form `compressed` and the cipher
+    /// ask for, copying them as they are if they already are in it.
+    fn restore(&self, stored: Vec<u8>, flags: u8, compressed: bool) -> Result<(u8, Vec<u8>)> {
+        let flags = flags & (RECORD_COMPRESSED | RECORD_ENCRYPTED);
+        let mut wanted = if compressed { RECORD_COMPRESSED } else { 0 };
+        if self.encrypts() {
+            wanted |= RECORD_ENCRYPTED;
+        }
+        if flags == wanted {
+            return Ok((flags, stored));
+        }
+        self.store(self.load(stored, flags)?, compressed)
     }
 
-    fn values(&mut self) -> Vec<V> {
-        let mut values = vec![];
-        for value in self.key_dir.keys() {
-            if let Some(v) = self.get(value) {
-                values.push(v);
-            }
+    #[cfg(feature = "encryption")]
+    fn encrypts(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn encrypts(&self) -> bool {
+        false
+    }
+
+    #[cfg(feature = "encryption")]
+    fn decrypt(&self, stored: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self
+            .cipher
+            .ok_or_else(|| anyhow!("value is encrypted and no cipher is set"))?;
+        if stored.len() < cipher.nonce_len() {
+            bail!("encrypted value is shorter than its nonce");
         }
-        values
+        let (nonce, ciphertext) = stored.split_at(cipher.nonce_len());
+        cipher.decrypt(nonce, ciphertext)
     }
 
-    fn items(&mut self) -> Vec<(&K, V)> {
-        let mut items = vec![];
-        self.key_dir.keys().for_each(|k| {
-            items.push((k, self.get(k).expect("Could not find key")));
-        });
-        items
+    #[cfg(not(feature = "encryption"))]
+    fn decrypt(&self, _stored: &[u8]) -> Result<Vec<u8>> {
+        bail!("value is encrypted; reading it needs the `encryption` feature")
     }
 }
 
-impl<K, V> ToDisk<K, V> for OnDisk<K, V>
-where
-    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
-    V: Serialize + DeserializeOwned,
-{
-    fn open(file_name: &str) -> Result<OnDisk<K, V>> {
-        let db_name = format!("{}.{}.db", file_name, 1);
-        let _ = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(db_name)?;
-        Ok(Self {
-            key_dir: BTreeMap::default(),
-            prefix: file_name.to_string(),
-            file_id: 1,
-            crc_hasher: Crc::<u32>::new(&CRC_32_CKSUM),
-            phantom_data: PhantomData,
-            file_position: 0,
-            is_dirty: false,
-            free_slots: BTreeMap::default(),
-            delete_map: BTreeMap::default(),
-        })
+/// Copies the given records of data file `file_id` into its temp file, in
+/// order, without decoding the values. Each record comes with its key
+/// already serialized. Used by `prune` to compact files on separate threads.
+fn copy_records(
+    checksum: Checksum,
+    prefix: &str,
+    file_id: u64,
+    records: Vec<(Vec<u8>, Entry)>,
+    compressed: bool,
+    format: ValueFormat,
+    separators: bool,
+) -> Result<Vec<Entry>> {
+    let mut source = File::open(format!("{}.{}.db", prefix, file_id))?;
+    let temp_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(format!("{}.{}.temp.db", prefix, file_id))?;
+    let mut writer = BufWriter::new(temp_file);
+
+    let mut position = 0;
+    let mut entries = Vec::with_capacity(records.len());
+    for (serialized_key, entry) in records {
+        let mut value_buf = vec![0u8; entry.value_len];
+        source.seek(SeekFrom::Start(compute_value_pos(
+            entry.value_len,
+            &entry.slot,
+        )))?;
+        source.read_exact(&mut value_buf)?;
+        let (flags, value_buf) = format.restore(value_buf, entry.flags, compressed)?;
+
+        let record = encode_record(
+            checksum,
+            flags,
+            entry.key_flags,
+            entry.seq,
+            entry.timestamp,
+            entry.expires_at,
+            &serialized_key,
+            &value_buf,
+        );
+        if separators {
+            writer.write_all(&RECORD_SEPARATOR)?;
+            position += RECORD_SEPARATOR.len() as u64;
+        }
+        writer.write_all(&record)?;
+        let end = position + record.len() as u64;
+        entries.push(Entry {
+            file_id,
+            value_len: value_buf.len(),
+            flags,
+            key_flags: entry.key_flags,
+            seq: entry.seq,
+            timestamp: entry.timestamp,
+            expires_at: entry.expires_at,
+            slot: Slot {
+                file_id,
+                start: position,
+                end,
+            },
+        });
+        position = end;
     }
+    writer.flush()?;
+    Ok(entries)
+}
 
-    fn sync(&mut self) -> Result<()> {
-        if self.is_dirty {
-            self.file_id += 1;
-            let db_name = format!("{}.{}.db", self.prefix, self.file_id);
-            let _ = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(db_name)?;
-            self.file_position = 0;
-            self.is_dirty = false;
+/// The temp files a rewrite pass appends to, each kept open behind one
+/// buffered writer along with its length until the pass flushes them.
+#[derive(Default)]
+struct TempWriters {
+    files: BTreeMap<u64, (BufWriter<File>, u64)>,
+}
+
+impl TempWriters {
+    fn flush(&mut self) -> Result<()> {
+        for (writer, _) in self.files.values_mut() {
+            writer.flush()?;
         }
         Ok(())
     }
+}
 
-    fn prune(&mut self) -> Result<()> {
-        // for every file in 1..self.file_id
-        // we want to iterate through and copy
-        if self.is_dirty {
-            let mut files_to_swap = BTreeSet::new();
-            let mut new_key_dir = BTreeMap::new();
-            if self.key_dir.is_empty() {
-                for f_id in 2..=self.file_id {
-                    fs::remove_file(format!("{}.{}.db", self.prefix, f_id))?;
-                }
-            }
-            for (key, (file_id, value_len, value_pos, Slot { .. })) in &self.key_dir {
-                let tempfile = self.get_tempfile_by_id(*file_id)?;
-                let file = self.get_file_by_id(*file_id)?;
-
-                let mut reader = BufReader::new(file);
-                reader.seek(SeekFrom::Start(*value_pos))?;
+/// A record read back from a data file.
+struct RawRecord {
+    flags: u8,
+    key_flags: u32,
+    seq: u64,
+    timestamp: u64,
+    expires_at: u64,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    start: u64,
+    end: u64,
+}
 
-                let mut value_buf = vec![0u8; *value_len];
-                reader.read_exact(&mut value_buf)?;
+/// Reads the record at `start` from a reader positioned there, skipping over
+/// fillers. Returns `None` at the end of the file and an error if the record
+/// is truncated or fails its checksum.
+fn read_record<R: Read>(
+    reader: &mut R,
+    mut start: u64,
+    file_len: u64,
+) -> Result<Option<RawRecord>> {
+    loop {
+        if start == file_len {
+            return Ok(None);
+        }
+        if file_len - start < HEADER_LEN {
+            bail!("truncated record header at offset {}", start);
+        }
+        let mut header = [0u8; HEADER_LEN as usize];
+        reader.read_exact(&mut header[..RECORD_SEPARATOR.len()])?;
+        if header[..RECORD_SEPARATOR.len()] == RECORD_SEPARATOR {
+            start += RECORD_SEPARATOR.len() as u64;
+            continue;
+        }
+        reader.read_exact(&mut header[RECORD_SEPARATOR.len()..])?;
+        let field = |at: usize| u64::from_be_bytes(header[at..at + 8].try_into().unwrap());
+        let checksum = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let seq = field(9);
+        let key_len = field(33);
+        let body_len = key_len
+            .checked_add(field(41))
+            .filter(|body_len| *body_len <= file_len - start - HEADER_LEN)
+            .ok_or_else(|| anyhow!("record at offset {} runs past the end of the file", start))?;
 
-                let value: V = bincode::deserialize(&value_buf)?;
+        let mut body = vec![0u8; body_len as usize];
+        reader.read_exact(&mut body)?;
+        let mut digest = Checksum::of_flags(header[4])?.crc().digest();
+        digest.update(&header[4..]);
+        digest.update(&body);
+        if digest.finalize() != checksum {
+            bail!("checksum mismatch in record at offset {}", start);
+        }
 
-                // then write it to tempfile
-                let (file_id, value_len, value_pos, new_slot) =
-                    self.serialize_to_file(key, value, tempfile)?;
-                new_key_dir.insert(key.clone(), (file_id, value_len, value_pos, new_slot));
+        let end = start + HEADER_LEN + body_len;
+        if seq == FILLER_SEQ {
+            start = end;
+            continue;
+        }
+        let value = body.split_off(key_len as usize);
+        return Ok(Some(RawRecord {
+            flags: header[4],
+            key_flags: u32::from_be_bytes(header[5..9].try_into().unwrap()),
+            seq,
+            timestamp: field(17),
+            expires_at: field(25),
+            key: body,
+            value,
+            start,
+            end,
+        }));
+    }
+}
 
-                // Finally, swap tempfile and file
-                files_to_swap.insert(file_id);
+/// Calls `f` with each record of the data file at `path` in order, and
+/// returns the first error hit parsing one. Past a record that fails to parse,
+/// scanning picks up again at the next record separator if there is one, and
+/// otherwise stops.
+fn scan_file(path: &str, mut f: impl FnMut(RawRecord)) -> Result<Option<anyhow::Error>> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut position = 0;
+    let mut first_error = None;
+    loop {
+        match read_record(&mut reader, position, file_len) {
+            Ok(Some(record)) => {
+                position = record.end;
+                f(record);
             }
-
-            for file_id in files_to_swap {
-                let temp_file_path = format!("{}.{}.temp.db", self.prefix, file_id);
-                let file_path = format!("{}.{}.db", self.prefix, file_id);
-                if fs::try_exists(&temp_file_path).is_ok() {
-                    fs::rename(temp_file_path, file_path)?;
-                }
+            Ok(None) => return Ok(first_error),
+            Err(e) => {
+                first_error.get_or_insert(e);
+                let mut rest = vec![];
+                reader.seek(SeekFrom::Start(position + 1))?;
+                reader.read_to_end(&mut rest)?;
+                let Some(skip) = rest
+                    .windows(RECORD_SEPARATOR.len())
+                    .position(|window| window == RECORD_SEPARATOR)
+                else {
+                    return Ok(first_error);
+                };
+                position += 1 + skip as u64;
+                reader.seek(SeekFrom::Start(position))?;
             }
+        }
+    }
+}
 
-            self.delete_map = BTreeMap::new();
-            self.key_dir = new_key_dir;
-            self.is_dirty = false;
+/// Finds where the data file at `path` stops parsing, when what follows is
+/// one record running to or past the end of the file, as an append cut short
+/// by a crash leaves it. Returns `None` if the whole file parses, or if the
+/// damage has more of the file after it.
+fn torn_tail(path: &str) -> Result<Option<u64>> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut position = 0;
+    loop {
+        match read_record(&mut reader, position, file_len) {
+            Ok(Some(record)) => position = record.end,
+            Ok(None) => return Ok(None),
+            Err(_) => break,
         }
+    }
 
-        Ok(())
+    let mut header = vec![];
+    reader.seek(SeekFrom::Start(position))?;
+    reader
+        .by_ref()
+        .take(RECORD_SEPARATOR.len() as u64 + HEADER_LEN)
+        .read_to_end(&mut header)?;
+    let mut start = position;
+    if header.starts_with(&RECORD_SEPARATOR) {
+        header.drain(..RECORD_SEPARATOR.len());
+        start += RECORD_SEPARATOR.len() as u64;
+    }
+    let rest = file_len - start;
+    if rest < HEADER_LEN {
+        return Ok(Some(position));
     }
+    let field = |at: usize| u64::from_be_bytes(header[at..at + 8].try_into().unwrap());
+    let runs_to_end = field(33)
+        .checked_add(field(41))
+        .and_then(|body_len| body_len.checked_add(HEADER_LEN))
+        .is_none_or(|record_len| record_len >= rest);
+    Ok(runs_to_end.then_some(position))
 }
 
-use Op::*;
-#[derive(Debug, Clone)]
-#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
-pub enum Op<K, V> {
-    Put { key: K, value: V },
-    Delete { key: K },
-    Update { key: K, value: V },
-    Prune,
-    Sync,
+/// The directory the files of the database at `prefix` live in.
+fn data_dir(prefix: &str) -> &Path {
+    match Path::new(prefix).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
 }
 
-pub fn eval_op<K, V>(db: &mut OnDisk<K, V>, op: Op<K, V>)
-where
-    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
-    V: Serialize + DeserializeOwned,
-{
-    match op {
-        Put { key, value } => {
-            db.put(key, value).unwrap();
-        }
-        Delete { key } => {
-            db.delete(&key).unwrap();
+/// Lists the ids of the `{prefix}.{id}.db` data files that exist, in order.
+fn data_file_ids(prefix: &str) -> Result<Vec<u64>> {
+    file_ids(prefix, ".db")
+}
+
+/// Lists the ids of the `{prefix}.{id}{suffix}` files that exist, in order.
+fn file_ids(prefix: &str, suffix: &str) -> Result<Vec<u64>> {
+    let path = Path::new(prefix);
+    let dir = data_dir(prefix);
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("invalid database prefix {}", prefix))?;
+
+    let mut file_ids = vec![];
+    for dir_entry in fs::read_dir(dir)? {
+        let file_name = dir_entry?.file_name();
+        let file_id = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(name))
+            .and_then(|rest| rest.strip_prefix('.'))
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .and_then(|file_id| file_id.parse::<u64>().ok());
+        file_ids.extend(file_id);
+    }
+    file_ids.sort();
+    Ok(file_ids)
+}
+
+/// Marks the files `prune` compacted into, or `replace_all` wrote the new
+/// dataset to, as complete, so they replace the data files even if a crash
+/// cuts the swap short. Holds how many there are.
+fn prune_marker_path(prefix: &str) -> String {
+    format!("{}.prune", prefix)
+}
+
+/// Swaps the `{prefix}.{id}.compacted.db` files of a `prune` whose marker is
+/// down in for the data files. Every step can be redone, so a swap cut short
+/// is finished by running it again.
+fn swap_in_compacted_files(prefix: &str, compacted_files: u64) -> Result<()> {
+    for file_id in data_file_ids(prefix)? {
+        let hint_path = format!("{}.{}.hint.db", prefix, file_id);
+        if fs::exists(&hint_path)? {
+            fs::remove_file(hint_path)?;
         }
-        Update { key, value } => {
-            db.put(key, value).unwrap();
+    }
+    for file_id in 1..=compacted_files {
+        let compacted_path = format!("{}.{}.compacted.db", prefix, file_id);
+        if fs::exists(&compacted_path)? {
+            fs::rename(compacted_path, format!("{}.{}.db", prefix, file_id))?;
         }
-        Prune => {
-            db.prune().unwrap();
+    }
+    for file_id in data_file_ids(prefix)? {
+        if file_id > compacted_files {
+            fs::remove_file(format!("{}.{}.db", prefix, file_id))?;
         }
-        Sync => db.sync().unwrap(),
     }
+    File::open(data_dir(prefix))?.sync_all()?;
+    fs::remove_file(prune_marker_path(prefix))?;
+    File::open(data_dir(prefix))?.sync_all()?;
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Deals with what a `prune` interrupted by a crash left behind: a swap with
+/// its marker down is finished, and the files of any other are removed.
+fn settle_interrupted_prune(prefix: &str) -> Result<()> {
+    let compacted_files = match fs::read_to_string(prune_marker_path(prefix)) {
+        // a marker cut short by the crash was never fully down
+        Ok(contents) => contents.trim().parse::<u64>().ok(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(compacted_files) = compacted_files {
+        return swap_in_compacted_files(prefix, compacted_files);
+    }
+    if fs::exists(prune_marker_path(prefix))? {
+        fs::remove_file(prune_marker_path(prefix))?;
+    }
+    for suffix in [".compacted.db", ".temp.db"] {
+        for file_id in file_ids(prefix, suffix)? {
+            fs::remove_file(format!("{}.{}{}", prefix, file_id, suffix))?;
+        }
+    }
+    Ok(())
+}
+
+/// One record of a data file, as read by `SingleFileReader`. Dead records,
+/// since deleted or overwritten, are included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRecord<K, V> {
+    pub key: K,
+    /// `None` for a tombstone left by a delete, and for an alias.
+    pub value: Option<V>,
+    /// For an alias, the sequence number of the record in the same file
+    /// holding the key's value.
+    pub alias_of: Option<u64>,
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch at which the value was put.
+    pub timestamp: u64,
+    /// Milliseconds since the Unix epoch at which the value expires, or 0.
+    pub expires_at: u64,
+    pub key_flags: u32,
+    pub compressed: bool,
+    /// Byte range of the whole record in the file.
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The records of a single data file, parsed on their own without the rest of
+/// the database. Obtained from `open_single_file`, or from `open` for a
+/// database with another codec. Values are decoded like `OnDisk` does, with
+/// the compressor and cipher set here.
+pub struct SingleFileReader<K, V, C = BincodeCodec> {
+    records: Vec<RawRecord>,
+    corruption: Option<String>,
+    compressor: Option<Box<dyn Compressor>>,
+    #[cfg(feature = "encryption")]
+    cipher: Option<Box<dyn Cipher>>,
+    phantom_data: PhantomData<(K, V, C)>,
+}
+
+/// Parses every record of the data file at `path`, such as `db.3.db`, for
+/// inspection. A corrupt record ends parsing but does not fail the call; the
+/// records before it stay available and `corruption` describes the problem.
+pub fn open_single_file<K, V>(path: &str) -> Result<SingleFileReader<K, V>>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    SingleFileReader::open(path)
+}
+
+impl<K, V, C> SingleFileReader<K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    /// Like `open_single_file`, for a file whose keys and values are encoded
+    /// with the codec `C`.
+    pub fn open(path: &str) -> Result<Self> {
+        let mut records = vec![];
+        let corruption = scan_file(path, |record| {
+            if record.flags & RECORD_COMMIT == 0 {
+                records.push(record);
+            }
+        })?;
+        Ok(SingleFileReader {
+            records,
+            corruption: corruption.map(|e| e.to_string()),
+            compressor: None,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+            phantom_data: PhantomData,
+        })
+    }
+
+    /// Decompresses values with `compressor`, as set on the database with
+    /// `OnDisk::set_compressor`, rather than deflate.
+    pub fn set_compressor(&mut self, compressor: Option<Box<dyn Compressor>>) {
+        self.compressor = compressor;
+    }
+
+    /// Decrypts values with `cipher`, as set on the database with
+    /// `OnDisk::set_cipher`.
+    #[cfg(feature = "encryption")]
+    pub fn set_cipher(&mut self, cipher: Option<Box<dyn Cipher>>) {
+        self.cipher = cipher;
+    }
+
+    fn value_format(&self) -> ValueFormat<'_> {
+        ValueFormat {
+            compressor: self.compressor.as_deref().unwrap_or(&Deflate),
+            #[cfg(feature = "encryption")]
+            cipher: self.cipher.as_deref(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Why parsing stopped early, if it did.
+    pub fn corruption(&self) -> Option<&str> {
+        self.corruption.as_deref()
+    }
+
+    /// Decodes the `index`th record of the file.
+    pub fn record(&self, index: usize) -> Result<FileRecord<K, V>> {
+        let raw = self
+            .records
+            .get(index)
+            .ok_or_else(|| anyhow!("no record {} in file", index))?;
+        let compressed = raw.flags & RECORD_COMPRESSED != 0;
+        let alias_of = match raw.flags & RECORD_ALIAS {
+            0 => None,
+            _ => Some(u64::from_be_bytes(raw.value.as_slice().try_into()?)),
+        };
+        let value = if raw.flags & RECORD_TOMBSTONE != 0 || alias_of.is_some() {
+            None
+        } else {
+            let value_bytes = self.value_format().load(raw.value.clone(), raw.flags)?;
+            Some(C::deserialize(&value_bytes)?)
+        };
+        Ok(FileRecord {
+            key: C::deserialize(&raw.key)?,
+            value,
+            alias_of,
+            seq: raw.seq,
+            timestamp: raw.timestamp,
+            expires_at: raw.expires_at,
+            key_flags: raw.key_flags,
+            compressed,
+            start: raw.start,
+            end: raw.end,
+        })
+    }
+
+    /// Decodes every record in file order.
+    pub fn iter(&self) -> impl Iterator<Item = Result<FileRecord<K, V>>> + '_ {
+        (0..self.records.len()).map(|index| self.record(index))
+    }
+}
+
+/// Returns whether the database at `prefix` was last closed cleanly, by
+/// `close` or by being dropped after a successful sync. Check this before
+/// opening it, as `open` clears the marker until the database is closed again.
+pub fn was_clean_shutdown(prefix: &str) -> Result<bool> {
+    Ok(fs::exists(format!("{}.clean", prefix))?)
+}
+
+/// How many data files `total_bytes` of records should take up when no file
+/// grows past `max_file_size`. A `max_file_size` of zero means no limit.
+pub fn recommended_file_count(total_bytes: u64, max_file_size: u64) -> u64 {
+    if max_file_size == 0 {
+        return 1;
+    }
+    total_bytes.div_ceil(max_file_size).max(1)
+}
+
+/// What `validate` found in a database's data files.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub files: usize,
+    pub records: usize,
+    /// One message per file that stopped parsing early.
+    pub errors: Vec<String>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// A summary of a database's size, returned by `OnDisk::stats`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DbStats {
+    pub live_keys: usize,
+    /// Keys deleted since the last `prune`, whose tombstones are still on disk.
+    pub tombstones: usize,
+    pub files: usize,
+    pub disk_bytes: u64,
+    /// Bytes held by deleted and overwritten records, which `prune` gives back.
+    pub reclaimable_bytes: u64,
+}
+
+impl DbStats {
+    /// The share of the bytes on disk that is dead space, from 0 to 1.
+    pub fn dead_space_ratio(&self) -> f64 {
+        if self.disk_bytes == 0 {
+            return 0.0;
+        }
+        self.reclaimable_bytes as f64 / self.disk_bytes as f64
+    }
+}
+
+/// Walks every data file of the database at `prefix`, checking that each
+/// record is complete and matches its checksum. Nothing is deserialized and
+/// no index is built, so this is cheaper than opening the database.
+pub fn validate(prefix: &str) -> Result<ValidationReport> {
+    let mut report = ValidationReport::default();
+    for file_id in data_file_ids(prefix)? {
+        let path = format!("{}.{}.db", prefix, file_id);
+        report.files += 1;
+        let mut records = 0;
+        let error = scan_file(&path, |_| records += 1)?;
+        report.records += records;
+        if let Some(e) = error {
+            report.errors.push(format!("{}: {}", path, e));
+        }
+    }
+    Ok(report)
+}
+
+/// The error reading a value returns when its record's checksum doesn't
+/// match its bytes, saying where the record is so it can be fetched again
+/// from elsewhere and put back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub file_id: u64,
+    pub offset: u64,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "checksum mismatch in record at offset {} of file {}",
+            self.offset, self.file_id
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// The value is always the last field of a record, so its position falls out
+/// of the record's slot and the value length instead of being kept per key.
+fn compute_value_pos(value_len: usize, slot: &Slot) -> u64 {
+    slot.end - value_len as u64
+}
+
+pub struct OnDisk<K, V, C = BincodeCodec>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    key_dir: BTreeMap<K, Entry>,
+    delete_map: BTreeMap<K, Entry>,
+    restorable: BTreeSet<K>,
+    prefix: String,
+    file_id: u64,
+    file_position: u64,
+    checksum: Checksum,
+    is_dirty: bool,
+    phantom_data: PhantomData<(V, C)>,
+    free_slots: FreeSlots,
+    slot_refs: BTreeMap<Slot, usize>,
+    dedup_on_prune: bool,
+    overwrite_counts: BTreeMap<K, u64>,
+    hot_key_threshold: Option<u64>,
+    /// Files a hot key left dead records in, for the next `sync` to rewrite.
+    hot_files: BTreeSet<u64>,
+    auto_compact_threshold: Option<f64>,
+    /// Combined length of the records live keys point at, spilled ones
+    /// included, kept up to date as keys are linked and unlinked.
+    live_bytes: u64,
+    value_cache: BTreeMap<K, V>,
+    compacting_through: Option<u64>,
+    next_seq: u64,
+    open_files: Mutex<Vec<(u64, File)>>,
+    max_open_files: usize,
+    active_writer: Option<BufWriter<File>>,
+    defer_flush: bool,
+    closed: bool,
+    read_only: bool,
+    read_ahead: usize,
+    read_ahead_buf: Mutex<Option<ReadAheadBuf>>,
+    disk_reads: AtomicU64,
+    snapshot_pin: Arc<()>,
+    deferred_slots: Vec<Slot>,
+    compress_on_prune: bool,
+    compressor: Option<Box<dyn Compressor>>,
+    #[cfg(feature = "encryption")]
+    cipher: Option<Box<dyn Cipher>>,
+    compaction_threads: usize,
+    key_dir_limit: Option<usize>,
+    spill_index: Vec<(K, u64)>,
+    spill_len: usize,
+    spill_shadowed: BTreeSet<K>,
+    /// Data files with an up to date hint file.
+    hinted_files: BTreeSet<u64>,
+    on_write: Option<ValueHook<V>>,
+    on_read: Option<ValueHook<V>>,
+    write_buffer: BTreeMap<K, (Vec<u8>, u32, u64)>,
+    write_buffer_limit: Option<usize>,
+    key_normalizer: Option<KeyNormalizer<K>>,
+    max_total_bytes: Option<u64>,
+    record_separators: bool,
+    max_file_size: Option<u64>,
+    unsynced_files: BTreeSet<u64>,
+    /// Records numbered below this were fsynced as of the last sync.
+    synced_seq: u64,
+    strict_durability: bool,
+    staged: BTreeMap<K, Option<Entry>>,
+    access_clock: AtomicU64,
+    access_times: Mutex<BTreeMap<K, u64>>,
+    /// The error that ended the last loop over `&OnDisk`.
+    iter_error: Mutex<Option<anyhow::Error>>,
+    #[cfg(feature = "mmap")]
+    use_mmap: bool,
+    #[cfg(feature = "mmap")]
+    mmaps: Mutex<BTreeMap<u64, Mmap>>,
+}
+
+/// A group of puts and deletes applied together, started by `OnDisk::begin`.
+///
+/// Nothing reaches the database until `commit`; reads through the
+/// transaction see its own writes first. Dropping it without committing is
+/// the same as `rollback`.
+pub struct Transaction<'a, K, V, C = BincodeCodec>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    db: &'a mut OnDisk<K, V, C>,
+    writes: BTreeMap<K, Option<V>>,
+}
+
+impl<K, V, C> Transaction<'_, K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    pub fn put(&mut self, key: K, value: V) {
+        let key = self.db.normalize_key(&key).into_owned();
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: &K) {
+        let key = self.db.normalize_key(key).into_owned();
+        self.writes.insert(key, None);
+    }
+
+    /// Writes every buffered operation to the database, all or nothing. An
+    /// error part way leaves the database as it was, and so does a crash
+    /// before the commit record is written.
+    pub fn commit(self) -> Result<()> {
+        self.db.commit_batch(self.writes)
+    }
+
+    /// Discards every buffered operation.
+    pub fn rollback(self) {}
+}
+
+impl<K, V, C> Transaction<'_, K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned + Clone,
+    C: Codec,
+{
+    /// Reads `key` as it would be after a commit.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.writes.get(&*self.db.normalize_key(key)) {
+            Some(value) => Ok(value.clone()),
+            None => self.db.get(key),
+        }
+    }
+}
+
+/// A transformation applied to values on their way to or from disk.
+// spelled out, as `Sync` alone names `Op::Sync` in this module
+type ValueHook<V> = Box<dyn Fn(V) -> V + Send + std::marker::Sync>;
+
+/// Maps a key to the form it is stored under.
+type KeyNormalizer<K> = Box<dyn Fn(&K) -> K + Send + std::marker::Sync>;
+
+/// A chunk of a data file read past the value that was asked for, so the
+/// records right after it can be served without another read.
+struct ReadAheadBuf {
+    file_id: u64,
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+/// An online compaction started by `OnDisk::begin_compaction`.
+///
+/// It covers every data file that existed when it began. The database keeps
+/// taking reads and writes while the live records are copied, and
+/// `finish_compaction` keeps whatever changed in the meantime.
+pub struct Compaction<K> {
+    snapshot: BTreeMap<K, Entry>,
+    last_file_id: u64,
+    /// Where each record copied so far went, by the slot it came from.
+    moved: BTreeMap<Slot, Entry>,
+    copied: BTreeMap<K, Entry>,
+    /// Set once every record is copied and the copy synced.
+    done: bool,
+}
+
+/// How many records `SharedDb::compact` copies per hold of the read lock.
+const COMPACTION_STEP_LEN: usize = 64;
+
+/// The live entries of a database at one point in time, taken by
+/// `OnDisk::snapshot` and read back with `OnDisk::iter_snapshot`.
+///
+/// While any snapshot is alive, the space of deleted and overwritten records
+/// is not reused and nothing that moves records (`prune`, `compact_deletes`,
+/// `replace_all`, compactions) may run, so every record a snapshot points at
+/// stays readable. Writes made after the snapshot was taken are not visible
+/// through it. Dropping the snapshot lifts these restrictions.
+pub struct Snapshot<K> {
+    entries: Vec<(K, Entry)>,
+    /// Serialized values of keys whose latest write was still buffered.
+    buffered: BTreeMap<K, Vec<u8>>,
+    _pin: Arc<()>,
+}
+
+impl<K> Snapshot<K> {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// A movable position over the keys of a database, taken by `OnDisk::cursor`.
+///
+/// The cursor starts unpositioned: `next` moves it to the first key and `prev`
+/// to the last. Values are only read when `value` is called. The keys are
+/// those live when the cursor was made.
+pub struct Cursor<'a, K, V, C = BincodeCodec>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    db: &'a OnDisk<K, V, C>,
+    keys: Vec<K>,
+    pos: Option<usize>,
+}
+
+impl<K, V, C> Cursor<'_, K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Moves to the first key at or after `key`. Returns false, leaving the
+    /// cursor unpositioned, if there is none.
+    pub fn seek(&mut self, key: &K) -> bool {
+        let key = &*self.db.normalize_key(key);
+        let i = self.keys.partition_point(|k| k < key);
+        self.pos = (i < self.keys.len()).then_some(i);
+        self.pos.is_some()
+    }
+
+    /// Moves to the next key. Returns false once it steps past the last one.
+    // not an `Iterator`, as the cursor can also move back
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> bool {
+        let i = self.pos.map_or(0, |i| i + 1);
+        self.pos = (i < self.keys.len()).then_some(i);
+        self.pos.is_some()
+    }
+
+    /// Moves to the previous key. Returns false once it steps before the
+    /// first one.
+    pub fn prev(&mut self) -> bool {
+        self.pos = match self.pos {
+            Some(i) => i.checked_sub(1),
+            None => self.keys.len().checked_sub(1),
+        };
+        self.pos.is_some()
+    }
+
+    pub fn key(&self) -> Option<&K> {
+        self.pos.map(|i| &self.keys[i])
+    }
+
+    pub fn value(&self) -> Result<Option<V>> {
+        match self.key() {
+            Some(key) => self.db.get(key),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A handle to a database that can be shared between threads. Clones share
+/// the same database; reads run side by side and each write has it to itself.
+pub struct SharedDb<K, V, C = BincodeCodec>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    db: Arc<RwLock<OnDisk<K, V, C>>>,
+}
+
+impl<K, V, C> Clone for SharedDb<K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    fn clone(&self) -> Self {
+        SharedDb {
+            db: Arc::clone(&self.db),
+        }
+    }
+}
+
+impl<K, V, C> SharedDb<K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    pub fn new(db: OnDisk<K, V, C>) -> Self {
+        SharedDb {
+            db: Arc::new(RwLock::new(db)),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.read().get(key)
+    }
+
+    pub fn put(&self, key: K, value: V) -> Result<Option<V>> {
+        self.write().put(key, value)
+    }
+
+    pub fn delete(&self, key: &K) -> Result<()> {
+        self.write().delete(key)
+    }
+
+    /// Runs an online compaction while other threads keep using the
+    /// database. The read lock is only held while copying a few records at a
+    /// time, so writers get in between, and the write lock to start and
+    /// finish.
+    pub fn compact(&self) -> Result<()> {
+        let mut compaction = self.write().begin_compaction()?;
+        while !self
+            .read()
+            .copy_compaction_step(&mut compaction, COMPACTION_STEP_LEN)?
+        {}
+        self.write().finish_compaction(compaction)
+    }
+
+    /// Locks the database for reading, for anything past a single `get`.
+    pub fn read(&self) -> RwLockReadGuard<'_, OnDisk<K, V, C>> {
+        self.db.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the database for writing, for anything past a single `put` or
+    /// `delete`.
+    pub fn write(&self) -> RwLockWriteGuard<'_, OnDisk<K, V, C>> {
+        self.db.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<K, V, C> OnDisk<K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Captures the live entries so they can be iterated later, regardless of
+    /// the writes made in between.
+    pub fn snapshot(&self) -> Result<Snapshot<K>> {
+        let mut entries: BTreeMap<K, Entry> = self.spilled_entries()?.into_iter().collect();
+        entries.extend(
+            self.key_dir
+                .iter()
+                .map(|(key, entry)| (key.clone(), entry.clone())),
+        );
+        let buffered = self
+            .write_buffer
+            .iter()
+            .map(|(key, (value_buf, ..))| (key.clone(), value_buf.clone()))
+            .collect();
+        Ok(Snapshot {
+            entries: entries.into_iter().collect(),
+            buffered,
+            _pin: Arc::clone(&self.snapshot_pin),
+        })
+    }
+
+    /// Iterates the entries of `snapshot` in key order, with their values as
+    /// of when it was taken.
+    pub fn iter_snapshot<'a>(
+        &'a self,
+        snapshot: &'a Snapshot<K>,
+    ) -> impl Iterator<Item = Result<(K, V)>> + 'a {
+        snapshot.entries.iter().map(|(key, entry)| {
+            let value = match snapshot.buffered.get(key) {
+                Some(value_buf) => self.apply_on_read(C::deserialize(value_buf)?),
+                None => self.load_value(entry)?,
+            };
+            Ok((key.clone(), value))
+        })
+    }
+
+    /// Writes the live entries to `out` as a stream of bincode-encoded
+    /// `(K, V)` pairs, the format `import_resumable` reads. The entries are
+    /// those of a snapshot taken first and released once they are written.
+    pub fn export_snapshot<W: Write>(&self, out: W) -> Result<()> {
+        let snapshot = self.snapshot()?;
+        self.export_from_snapshot(&snapshot, out)
+    }
+
+    /// Like `export_snapshot`, for a snapshot taken earlier. The snapshot
+    /// keeps the exported records in place, so the database can take writes
+    /// between taking it and exporting it.
+    pub fn export_from_snapshot<W: Write>(&self, snapshot: &Snapshot<K>, out: W) -> Result<()> {
+        let mut writer = BufWriter::new(out);
+        for item in self.iter_snapshot(snapshot) {
+            bincode::serialize_into(&mut writer, &item?)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes a compacted copy of the live records to a new database at
+    /// `dest_prefix`, leaving this one's files and keys as they are. Records
+    /// keep their sequence numbers, flags and expiry, and values are copied
+    /// as stored, so an encrypted database backs up encrypted.
+    pub fn backup(&mut self, dest_prefix: &str) -> Result<()> {
+        if !data_file_ids(dest_prefix)?.is_empty() {
+            bail!("{} already holds a database", dest_prefix);
+        }
+        self.flush_writes()?;
+        self.promote_staged()?;
+        let mut entries: BTreeMap<K, Entry> = self.spilled_entries()?.into_iter().collect();
+        entries.extend(
+            self.key_dir
+                .iter()
+                .map(|(key, entry)| (key.clone(), entry.clone())),
+        );
+
+        let backup_path = |file_id| format!("{}.{}.db", dest_prefix, file_id);
+        let mut file_id = 1;
+        let mut writer = BufWriter::new(File::create(backup_path(file_id))?);
+        let mut position = 0;
+        let now = now_millis();
+        for (key, entry) in entries.iter().filter(|(_, entry)| !entry.is_expired(now)) {
+            let mut record = encode_record(
+                self.checksum,
+                entry.flags,
+                entry.key_flags,
+                entry.seq,
+                entry.timestamp,
+                entry.expires_at,
+                &C::serialize(key)?,
+                &self.read_stored_bytes(entry)?,
+            );
+            if self.record_separators {
+                record.splice(0..0, RECORD_SEPARATOR);
+            }
+            let len = record.len() as u64;
+            if let Some(max_file_size) = self.max_file_size {
+                if position > 0 && position + len > max_file_size {
+                    writer.flush()?;
+                    writer.get_ref().sync_all()?;
+                    file_id += 1;
+                    writer = BufWriter::new(File::create(backup_path(file_id))?);
+                    position = 0;
+                }
+            }
+            writer.write_all(&record)?;
+            position += len;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Schema::of::<K, V, C>().write(dest_prefix)?;
+        File::open(data_dir(dest_prefix))?.sync_all()?;
+        Ok(())
+    }
+
+    fn snapshots_open(&self) -> bool {
+        Arc::strong_count(&self.snapshot_pin) > 1
+    }
+
+    /// Makes `slot` available to later puts, or holds on to it while a
+    /// snapshot may still read the record in it.
+    fn free_slot(&mut self, slot: Slot) {
+        if self.snapshots_open() {
+            self.deferred_slots.push(slot);
+            return;
+        }
+        for slot in std::mem::take(&mut self.deferred_slots)
+            .into_iter()
+            .chain([slot])
+        {
+            self.free_slots.insert(slot);
+        }
+    }
+
+    /// Opens the database at `file_name` with automatic compaction set up, as
+    /// by `set_auto_compact_threshold`, so deletes and overwrites made right
+    /// away count towards it too.
+    pub fn open_with_auto_compact(
+        file_name: &str,
+        auto_compact_threshold: Option<f64>,
+    ) -> Result<Self> {
+        let mut db = Self::open(file_name)?;
+        db.set_auto_compact_threshold(auto_compact_threshold);
+        Ok(db)
+    }
+
+    /// Opens the database at `file_name` only for reading. Nothing on disk is
+    /// changed, not even by recovery, so any number of processes can read the
+    /// files of a database one writer owns. Every write returns an error.
+    pub fn open_read_only(file_name: &str) -> Result<Self> {
+        if fs::exists(prune_marker_path(file_name))? {
+            bail!(
+                "{} has an interrupted prune, which a writable open finishes",
+                file_name
+            );
+        }
+        if data_file_ids(file_name)?.is_empty() {
+            bail!("no database at {}", file_name);
+        }
+        let mut db = Self::unopened(file_name, true);
+        db.recover()?;
+        Ok(db)
+    }
+
+    /// Creates a database at `file_name` holding the pairs of `iter`, written
+    /// as one batch. Fails if there already is a database there.
+    pub fn from_iter_at(file_name: &str, iter: impl IntoIterator<Item = (K, V)>) -> Result<Self> {
+        if !data_file_ids(file_name)?.is_empty() {
+            bail!("{} already holds a database", file_name);
+        }
+        let mut db = Self::open(file_name)?;
+        db.put_many(iter)?;
+        Ok(db)
+    }
+
+    /// The database at `prefix` with nothing read in yet.
+    fn unopened(prefix: &str, read_only: bool) -> Self {
+        Self {
+            key_dir: BTreeMap::default(),
+            prefix: prefix.to_string(),
+            file_id: 1,
+            checksum: Checksum::default(),
+            phantom_data: PhantomData,
+            file_position: 0,
+            is_dirty: false,
+            free_slots: FreeSlots::default(),
+            delete_map: BTreeMap::default(),
+            restorable: BTreeSet::default(),
+            slot_refs: BTreeMap::default(),
+            dedup_on_prune: false,
+            overwrite_counts: BTreeMap::default(),
+            hot_key_threshold: None,
+            hot_files: BTreeSet::default(),
+            auto_compact_threshold: None,
+            live_bytes: 0,
+            value_cache: BTreeMap::default(),
+            compacting_through: None,
+            next_seq: 0,
+            open_files: Mutex::default(),
+            max_open_files: 16,
+            active_writer: None,
+            defer_flush: false,
+            closed: false,
+            read_only,
+            read_ahead: 0,
+            read_ahead_buf: Mutex::default(),
+            disk_reads: AtomicU64::new(0),
+            snapshot_pin: Arc::default(),
+            deferred_slots: Vec::new(),
+            compress_on_prune: false,
+            compressor: None,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+            compaction_threads: 1,
+            key_dir_limit: None,
+            spill_index: Vec::new(),
+            spill_len: 0,
+            spill_shadowed: BTreeSet::default(),
+            hinted_files: BTreeSet::default(),
+            on_write: None,
+            on_read: None,
+            write_buffer: BTreeMap::default(),
+            write_buffer_limit: None,
+            key_normalizer: None,
+            max_total_bytes: None,
+            record_separators: false,
+            max_file_size: None,
+            unsynced_files: BTreeSet::default(),
+            synced_seq: 0,
+            strict_durability: false,
+            staged: BTreeMap::default(),
+            access_clock: AtomicU64::new(0),
+            access_times: Mutex::default(),
+            iter_error: Mutex::default(),
+            #[cfg(feature = "mmap")]
+            use_mmap: false,
+            #[cfg(feature = "mmap")]
+            mmaps: Mutex::default(),
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            bail!("the database was opened read-only");
+        }
+        Ok(())
+    }
+
+    fn check_no_snapshots(&self, operation: &str) -> Result<()> {
+        if self.snapshots_open() {
+            bail!("cannot {} while a snapshot is open", operation);
+        }
+        Ok(())
+    }
+
+    /// Starts an online compaction of every existing data file. New writes go
+    /// to a fresh file from here on, and free space in the compacted files is
+    /// no longer handed out.
+    pub fn begin_compaction(&mut self) -> Result<Compaction<K>> {
+        self.check_writable()?;
+        if self.compacting_through.is_some() {
+            bail!("a compaction is already in progress");
+        }
+        self.check_no_snapshots("compact")?;
+        self.flush_writes()?;
+        self.promote_staged()?;
+        self.load_spilled()?;
+        let last_file_id = self.file_id;
+        self.rotate()?;
+        self.free_slots = FreeSlots::default();
+        self.deferred_slots = Vec::new();
+        self.compacting_through = Some(last_file_id);
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(format!("{}.{}.temp.db", self.prefix, last_file_id))?;
+
+        Ok(Compaction {
+            snapshot: self.key_dir.clone(),
+            last_file_id,
+            moved: BTreeMap::new(),
+            copied: BTreeMap::new(),
+            done: false,
+        })
+    }
+
+    /// Copies the records live at the start of `compaction` into a new file.
+    /// This only reads the database, so it can overlap with other readers.
+    pub fn copy_compaction(&self, compaction: &mut Compaction<K>) -> Result<()> {
+        self.copy_compaction_step(compaction, usize::MAX)?;
+        Ok(())
+    }
+
+    /// Copies up to `limit` more records of `compaction`, picking up after
+    /// the last key copied. Returns whether the copy is complete.
+    fn copy_compaction_step(&self, compaction: &mut Compaction<K>, limit: usize) -> Result<bool> {
+        let Compaction {
+            snapshot,
+            last_file_id: file_id,
+            moved,
+            copied,
+            done,
+        } = compaction;
+        let file_id = *file_id;
+        if *done {
+            return Ok(true);
+        }
+        let start = match copied.last_key_value() {
+            Some((last, _)) => Bound::Excluded(last.clone()),
+            None => Bound::Unbounded,
+        };
+        let mut step = snapshot.range((start, Bound::Unbounded)).peekable();
+        let mut writers = TempWriters::default();
+        for (key, entry) in step.by_ref().take(limit) {
+            let new_entry = match moved.get(&entry.slot) {
+                Some(new_entry) => {
+                    self.alias_to_file(&mut writers, key, new_entry.seq, entry, file_id)?;
+                    Entry {
+                        seq: entry.seq,
+                        timestamp: entry.timestamp,
+                        ..new_entry.clone()
+                    }
+                }
+                None => {
+                    let value = self.read_value(entry)?;
+                    let new_entry = self.serialize_to_file(
+                        &mut writers,
+                        key,
+                        value,
+                        entry.seq,
+                        entry.key_flags,
+                        entry.timestamp,
+                        entry.expires_at,
+                        file_id,
+                        false,
+                    )?;
+                    moved.insert(entry.slot.clone(), new_entry.clone());
+                    new_entry
+                }
+            };
+            copied.insert(key.clone(), new_entry);
+        }
+        writers.flush()?;
+        if step.peek().is_some() {
+            return Ok(false);
+        }
+        self.get_tempfile_by_id(file_id)?.sync_all()?;
+        *done = true;
+        Ok(true)
+    }
+
+    /// Swaps the compacted file in for the old ones. Keys written or deleted
+    /// since `begin_compaction` keep their newer state.
+    pub fn finish_compaction(&mut self, compaction: Compaction<K>) -> Result<()> {
+        if !compaction.done {
+            bail!("compaction has not been copied yet");
+        }
+        let copied = compaction.copied;
+        let last_file_id = compaction.last_file_id;
+
+        self.release_files();
+        self.remove_hint(last_file_id)?;
+        fs::rename(
+            format!("{}.{}.temp.db", self.prefix, last_file_id),
+            format!("{}.{}.db", self.prefix, last_file_id),
+        )?;
+        for f_id in 1..last_file_id {
+            self.remove_hint(f_id)?;
+            let old_file_path = format!("{}.{}.db", self.prefix, f_id);
+            if fs::exists(&old_file_path)? {
+                fs::remove_file(old_file_path)?;
+            }
+        }
+
+        for (key, new_entry) in copied {
+            if self.key_dir.get(&key) == compaction.snapshot.get(&key) {
+                self.link(key, new_entry);
+            }
+        }
+
+        let mut slot_refs = BTreeMap::new();
+        for entry in self.key_dir.values() {
+            *slot_refs.entry(entry.slot.clone()).or_insert(0) += 1;
+        }
+        slot_refs.retain(|_, refs| *refs > 1);
+        self.slot_refs = slot_refs;
+        self.delete_map
+            .retain(|_, entry| entry.file_id > last_file_id);
+        let delete_map = &self.delete_map;
+        self.restorable.retain(|key| delete_map.contains_key(key));
+        self.compacting_through = None;
+        self.spill_cold_entries()
+    }
+
+    /// Rewrites the data file `file_id` with only its live records, leaving
+    /// every other file alone. Returns how many bytes the file shrank by.
+    pub fn compact_file(&mut self, file_id: u64) -> Result<u64> {
+        self.check_writable()?;
+        if self.compacting_through.is_some() {
+            bail!("cannot compact while a compaction is in progress");
+        }
+        self.check_no_snapshots("compact")?;
+        let file_path = format!("{}.{}.db", self.prefix, file_id);
+        if !fs::exists(&file_path)? {
+            bail!("data file {} does not exist", file_id);
+        }
+        self.flush_writes()?;
+        self.promote_staged()?;
+        self.load_spilled()?;
+        let before = fs::metadata(&file_path)?.len();
+        self.rewrite_file(file_id)?;
+        let after = fs::metadata(&file_path)?.len();
+        self.spill_cold_entries()?;
+        Ok(before - after)
+    }
+
+    /// Describes the types and layout this database writes.
+    pub fn schema(&self) -> Schema {
+        Schema::of::<K, V, C>()
+    }
+
+    /// Imports a stream of bincode-encoded `(K, V)` pairs, recording progress
+    /// in the `checkpoint` file as it goes. If a previous import of the same
+    /// input was interrupted, this picks up from its last checkpoint; records
+    /// after that point are simply put again. The checkpoint is removed once
+    /// the whole input is in.
+    pub fn import_resumable<R: Read + Seek>(
+        &mut self,
+        mut input: R,
+        checkpoint: &str,
+    ) -> Result<()> {
+        let (mut imported, mut offset) = match fs::read(checkpoint) {
+            Ok(bytes) if bytes.len() == 16 => (
+                u64::from_be_bytes(bytes[..8].try_into()?),
+                u64::from_be_bytes(bytes[8..].try_into()?),
+            ),
+            Ok(_) => bail!("checkpoint {} is corrupt", checkpoint),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (0, 0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let input_len = input.seek(SeekFrom::End(0))?;
+        input.seek(SeekFrom::Start(offset))?;
+        while offset < input_len {
+            let (key, value): (K, V) = bincode::deserialize_from(&mut input)?;
+            self.write_value(key, value, 0, 0)?;
+            imported += 1;
+            offset = input.stream_position()?;
+
+            if imported % IMPORT_CHECKPOINT_INTERVAL == 0 {
+                // the records a checkpoint covers have to be durable before
+                // it is, or a crash could skip them on resuming
+                self.flush()?;
+                let mut bytes = imported.to_be_bytes().to_vec();
+                bytes.extend_from_slice(&offset.to_be_bytes());
+                let temp_checkpoint = format!("{}.tmp", checkpoint);
+                let file = File::create(&temp_checkpoint)?;
+                (&file).write_all(&bytes)?;
+                file.sync_all()?;
+                fs::rename(temp_checkpoint, checkpoint)?;
+                File::open(data_dir(checkpoint))?.sync_all()?;
+            }
+        }
+
+        if fs::exists(checkpoint)? {
+            fs::remove_file(checkpoint)?;
+        }
+        Ok(())
+    }
+
+    /// Iterates live entries in key order, reading each value only as the
+    /// iterator reaches it.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(K, V)>> + '_ {
+        let mut keys: Vec<Cow<'_, K>> = self.key_dir.keys().map(Cow::Borrowed).collect();
+        let spill_error = match self.spilled_entries() {
+            Ok(spilled) => {
+                keys.extend(spilled.into_iter().map(|(key, _)| Cow::Owned(key)));
+                keys.sort();
+                None
+            }
+            Err(e) => Some(Err(e)),
+        };
+        spill_error.into_iter().chain(keys.into_iter().map(|key| {
+            let value = self
+                .get(&key)?
+                .ok_or_else(|| anyhow!("could not find key"))?;
+            Ok((key.into_owned(), value))
+        }))
+    }
+
+    /// Takes the error that cut the last loop over `&db` short, if one did.
+    /// Iterating a reference yields bare pairs, so an error reading a value
+    /// ends the loop and waits here instead.
+    pub fn take_iter_error(&self) -> Option<anyhow::Error> {
+        self.iter_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+
+    /// Iterates live entries in the order they were written instead of key
+    /// order. Values are read as the iterator advances.
+    pub fn iter_by_sequence(&self) -> impl Iterator<Item = Result<(K, V)>> + '_ {
+        let mut entries: Vec<_> = self
+            .key_dir
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect();
+        let spill_error = match self.spilled_entries() {
+            Ok(spilled) => {
+                entries.extend(spilled);
+                None
+            }
+            Err(e) => Some(Err(e)),
+        };
+        entries.sort_by_key(|(_, entry)| entry.seq);
+        spill_error.into_iter().chain(
+            entries
+                .into_iter()
+                .map(|(key, entry)| Ok((key, self.load_value(&entry)?))),
+        )
+    }
+
+    /// Returns `(file_id, start, end)` of the record holding `key`, for
+    /// relating keys to their bytes on disk. The range comes from the index;
+    /// a `Result` because a key spilled by `set_key_dir_limit` has its entry
+    /// read back from the spill file.
+    pub fn byte_range(&self, key: &K) -> Result<Option<(u64, u64, u64)>> {
+        let key = &*self.normalize_key(key);
+        Ok(self
+            .lookup(key)?
+            .map(|entry| (entry.slot.file_id, entry.slot.start, entry.slot.end)))
+    }
+
+    /// Returns the keys whose records end past the end of their data file, as
+    /// happens when a file is truncated behind the index's back. Only file
+    /// sizes are looked at, so this is much cheaper than `validate`.
+    pub fn dangling_keys(&self) -> Result<Vec<K>> {
+        let mut file_lens = BTreeMap::new();
+        let mut dangling = vec![];
+        let spilled = self.spilled_entries()?;
+        for (key, entry) in self
+            .key_dir
+            .iter()
+            .chain(spilled.iter().map(|(k, e)| (k, e)))
+        {
+            let file_len = match file_lens.get(&entry.file_id) {
+                Some(file_len) => *file_len,
+                None => {
+                    let path = format!("{}.{}.db", self.prefix, entry.file_id);
+                    let file_len = match fs::metadata(path) {
+                        Ok(metadata) => metadata.len(),
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                        Err(e) => return Err(e.into()),
+                    };
+                    file_lens.insert(entry.file_id, file_len);
+                    file_len
+                }
+            };
+            let value_pos = compute_value_pos(entry.value_len, &entry.slot);
+            if value_pos + entry.value_len as u64 > file_len {
+                dangling.push(key.clone());
+            }
+        }
+        dangling.sort();
+        Ok(dangling)
+    }
+
+    /// Reads the record of every live key and returns the keys whose record
+    /// fails its checksum or can't be read at all. Unlike `validate`, dead
+    /// records are skipped and the blame lands on keys.
+    pub fn verify(&self) -> Result<Vec<K>> {
+        let spilled = self.spilled_entries()?;
+        let mut corrupt = vec![];
+        for (key, entry) in self
+            .key_dir
+            .iter()
+            .chain(spilled.iter().map(|(k, e)| (k, e)))
+        {
+            if self.read_stored_bytes(entry).is_err() {
+                corrupt.push(key.clone());
+            }
+        }
+        corrupt.sort();
+        Ok(corrupt)
+    }
+
+    /// Deletes `key` but leaves its record in place, so `restore` can bring it
+    /// back until the next `prune` or a rewrite of the file holding it. The
+    /// delete is not written down until then either, so reopening the
+    /// database brings the key back.
+    pub fn soft_delete(&mut self, key: &K) -> Result<()> {
+        self.check_writable()?;
+        let key = &*self.normalize_key(key);
+        // the record left in place must hold the latest value
+        self.flush_writes()?;
+        self.promote_staged()?;
+        self.unspill(key)?;
+        self.value_cache.remove(key);
+        if let Some(entry) = self.key_dir.remove(key) {
+            self.live_bytes -= entry.slot.end - entry.slot.start;
+            // the slot is neither freed nor released from sharing, so nothing
+            // can be written over the record while it is restorable
+            self.delete_map.insert(key.clone(), entry);
+            self.restorable.insert(key.clone());
+            self.is_dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Re-links a soft-deleted `key` to its record. Returns false if the key
+    /// was not soft-deleted or its record has since been reclaimed.
+    pub fn restore(&mut self, key: &K) -> Result<bool> {
+        self.check_writable()?;
+        let key = &*self.normalize_key(key);
+        if !self.restorable.remove(key) {
+            return Ok(false);
+        }
+        match self.delete_map.remove(key) {
+            Some(entry) => {
+                self.link(key.clone(), entry);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Puts every entry, flushing the active file once at the end rather than
+    /// after each record.
+    pub fn put_many(&mut self, entries: impl IntoIterator<Item = (K, V)>) -> Result<()> {
+        self.batched(|db| {
+            for (key, value) in entries {
+                db.write_value(key, value, 0, 0)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Deletes every key, flushing the active file once at the end rather
+    /// than after each tombstone.
+    pub fn delete_many<'k>(&mut self, keys: impl IntoIterator<Item = &'k K>) -> Result<()>
+    where
+        K: 'k,
+    {
+        self.batched(|db| {
+            for key in keys {
+                db.delete(key)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Applies the operations of a transaction. Every record goes to disk,
+    /// followed by a commit record, before the index changes, and `open` drops
+    /// the records of a transaction whose commit record is missing.
+    fn commit_batch(&mut self, writes: BTreeMap<K, Option<V>>) -> Result<()> {
+        self.check_writable()?;
+        // serialized up front, so a value that fails to leaves nothing behind;
+        // the last operation on each key, already normalized, is all that
+        // needs writing
+        let mut batch = vec![];
+        for (key, value) in writes {
+            let stored = match value {
+                Some(value) => {
+                    let value = match &self.on_write {
+                        Some(on_write) => on_write(value),
+                        None => value,
+                    };
+                    let serialized_value = C::serialize(&value)?;
+                    Some(
+                        self.value_format()
+                            .store(serialized_value, self.compressor.is_some())?,
+                    )
+                }
+                None => {
+                    let live = match self.staged.get(&key) {
+                        Some(entry) => entry.is_some(),
+                        None => self.lookup(&key)?.is_some(),
+                    };
+                    if !live {
+                        continue;
+                    }
+                    None
+                }
+            };
+            batch.push((C::serialize(&key)?, key, stored));
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.defer_flush = true;
+        let appended = self.append_batch(batch);
+        self.defer_flush = false;
+        let entries = appended?;
+        self.flush_active_writer()?;
+        if self.strict_durability {
+            for &file_id in &self.unsynced_files {
+                self.get_file_by_id(file_id)?.sync_all()?;
+            }
+        }
+
+        for (key, entry) in entries {
+            self.staged.remove(&key);
+            self.unlink(&key)?;
+            if let Some(entry) = entry {
+                self.restorable.remove(&key);
+                self.touch(&key);
+                self.link(key, entry);
+            }
+        }
+        self.spill_cold_entries()?;
+        self.maybe_auto_compact()
+    }
+
+    /// Appends the records of a transaction and its commit record, returning
+    /// the entries they give each key, `None` for deletes.
+    #[allow(clippy::type_complexity)]
+    fn append_batch(
+        &mut self,
+        batch: Vec<(Vec<u8>, K, Option<(u8, Vec<u8>)>)>,
+    ) -> Result<Vec<(K, Option<Entry>)>> {
+        let first_seq = self.next_seq;
+        let timestamp = now_millis();
+        let mut entries = vec![];
+        for (serialized_key, key, stored) in batch {
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            let deleted = stored.is_none();
+            let (flags, serialized_value) = stored.unwrap_or((RECORD_TOMBSTONE, vec![]));
+            let record = encode_record(
+                self.checksum,
+                flags | RECORD_BATCH,
+                0,
+                seq,
+                timestamp,
+                0,
+                &serialized_key,
+                &serialized_value,
+            );
+            let slot = self.append_record(&record)?;
+            let entry = (!deleted).then_some(Entry {
+                file_id: slot.file_id,
+                value_len: serialized_value.len(),
+                flags,
+                key_flags: 0,
+                seq,
+                timestamp,
+                expires_at: 0,
+                slot,
+            });
+            entries.push((key, entry));
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let commit = encode_record(
+            self.checksum,
+            RECORD_COMMIT,
+            0,
+            seq,
+            timestamp,
+            0,
+            &[],
+            &first_seq.to_be_bytes(),
+        );
+        self.append_record(&commit)?;
+        Ok(entries)
+    }
+
+    fn batched(&mut self, f: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        self.defer_flush = true;
+        let result = f(self);
+        self.defer_flush = false;
+        let flushed = self.flush_active_writer();
+        result.and(flushed)?;
+        self.maybe_auto_compact()
+    }
+
+    /// Starts a transaction. The database can't be used directly until it is
+    /// committed or rolled back.
+    pub fn begin(&mut self) -> Transaction<'_, K, V, C> {
+        Transaction {
+            db: self,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Returns a cursor over the live keys, for stepping through them in
+    /// either direction.
+    pub fn cursor(&self) -> Result<Cursor<'_, K, V, C>> {
+        Ok(Cursor {
+            db: self,
+            keys: self.keys_owned()?,
+            pos: None,
+        })
+    }
+
+    /// Returns the entries whose keys fall in `range`, in key order. Only
+    /// those values are read.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, V)>> {
+        let mut keys: Vec<K> = self
+            .spilled_entries()?
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| range.contains(key))
+            .collect();
+        keys.extend(
+            self.key_dir
+                .range((range.start_bound(), range.end_bound()))
+                .map(|(key, _)| key.clone()),
+        );
+        keys.sort();
+
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(&key)? {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns every entry whose key starts with the bytes of `prefix`, in key
+    /// order. For keys like `String` and `Vec<u8>`, which sort by their bytes,
+    /// those keys sit next to each other in the index.
+    pub fn scan_prefix(&self, prefix: &K) -> Result<Vec<(K, V)>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let has_prefix = |key: &K| key.as_ref().starts_with(prefix.as_ref());
+        let mut keys: Vec<K> = self
+            .spilled_entries()?
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(has_prefix)
+            .collect();
+        keys.extend(
+            self.key_dir
+                .range(prefix..)
+                .map(|(key, _)| key)
+                .take_while(|key| has_prefix(key))
+                .cloned(),
+        );
+        keys.sort();
+
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(&key)? {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns the key right after `key` in sorted order, whether or not `key`
+    /// itself exists. Keys spilled by `set_key_dir_limit` count too, found by
+    /// reading the spill file from the block that would hold `key`.
+    pub fn next_key(&self, key: &K) -> Result<Option<K>> {
+        let key = &*self.normalize_key(key);
+        let in_memory = self
+            .key_dir
+            .range((Bound::Excluded(key), Bound::Unbounded))
+            .next()
+            .map(|(key, _)| key);
+        let start = self.spill_index.partition_point(|(first, _)| first <= key);
+        for block in start.saturating_sub(1)..self.spill_index.len() {
+            // blocks from here on only hold keys past the one in memory
+            if in_memory.is_some_and(|next| self.spill_index[block].0 > *next) {
+                break;
+            }
+            let spilled = self
+                .spill_block(block)?
+                .into_iter()
+                .map(|(spilled_key, _)| spilled_key)
+                .find(|spilled_key| {
+                    spilled_key > key && !self.spill_shadowed.contains(spilled_key)
+                });
+            if let Some(spilled) = spilled {
+                return Ok(Some(match in_memory {
+                    Some(next) if next < &spilled => next.clone(),
+                    _ => spilled,
+                }));
+            }
+        }
+        Ok(in_memory.cloned())
+    }
+
+    /// Returns the key right before `key` in sorted order, whether or not
+    /// `key` itself exists. Keys spilled by `set_key_dir_limit` count too,
+    /// found by reading the spill file back from the block that would hold
+    /// `key`.
+    pub fn prev_key(&self, key: &K) -> Result<Option<K>> {
+        let key = &*self.normalize_key(key);
+        let in_memory = self.key_dir.range(..key).next_back().map(|(key, _)| key);
+        let end = self.spill_index.partition_point(|(first, _)| first < key);
+        for block in (0..end).rev() {
+            // blocks from here on back only hold keys before the one in memory
+            if in_memory.is_some_and(|prev| {
+                self.spill_index
+                    .get(block + 1)
+                    .is_some_and(|(first, _)| first <= prev)
+            }) {
+                break;
+            }
+            let spilled = self
+                .spill_block(block)?
+                .into_iter()
+                .map(|(spilled_key, _)| spilled_key)
+                .filter(|spilled_key| {
+                    spilled_key < key && !self.spill_shadowed.contains(spilled_key)
+                })
+                .last();
+            if let Some(spilled) = spilled {
+                return Ok(Some(match in_memory {
+                    Some(prev) if prev > &spilled => prev.clone(),
+                    _ => spilled,
+                }));
+            }
+        }
+        Ok(in_memory.cloned())
+    }
+
+    /// Runs every value through `on_write` before it is stored and through
+    /// `on_read` after it is read back. Values moved by compactions are left
+    /// as stored.
+    pub fn set_value_middleware<F, G>(&mut self, on_write: F, on_read: G)
+    where
+        F: Fn(V) -> V + Send + std::marker::Sync + 'static,
+        G: Fn(V) -> V + Send + std::marker::Sync + 'static,
+    {
+        self.on_write = Some(Box::new(on_write));
+        self.on_read = Some(Box::new(on_read));
+    }
+
+    /// Maps every key given to `put`, `get` and `delete` through `normalize`
+    /// first, so keys that only differ in, say, case share one entry. Keys
+    /// already stored are not normalized, so set this before writing.
+    pub fn set_key_normalizer<F>(&mut self, normalize: F)
+    where
+        F: Fn(&K) -> K + Send + std::marker::Sync + 'static,
+    {
+        self.key_normalizer = Some(Box::new(normalize));
+    }
+
+    /// Returns a copy of every live key in order. Unlike `keys`, the result
+    /// does not borrow the database, so it can be modified while going
+    /// through them.
+    pub fn keys_owned(&self) -> Result<Vec<K>> {
+        let spilled = self.spilled_entries()?;
+        let mut keys: Vec<K> = self.key_dir.keys().cloned().collect();
+        keys.extend(spilled.into_iter().map(|(key, _)| key));
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Puts every live key of `other` into this database. Where both have a
+    /// key, the value put last wins, going by their timestamps, and a tie
+    /// keeps this database's. Merged keys keep their flags and expiry but are
+    /// timestamped with the time of the merge.
+    pub fn merge_from(&mut self, other: &OnDisk<K, V, C>) -> Result<()> {
+        for key in other.keys_owned()? {
+            let Some(value) = other.get(&key)? else {
+                continue;
+            };
+            let theirs = other.timestamp(&key)?;
+            if self.contains_key(&key)? && self.timestamp(&key)? >= theirs {
+                continue;
+            }
+            let expires_at = match other.write_buffer.get(&key) {
+                Some(_) => 0,
+                None => other.lookup(&key)?.map_or(0, |entry| entry.expires_at),
+            };
+            let key_flags = other.flags_of(&key)?.unwrap_or(0);
+            self.write_value(key, value, key_flags, expires_at)?;
+        }
+        Ok(())
+    }
+
+    /// Copies every live key into a new database at `new_prefix` that encodes
+    /// keys and values with `C2`, for moving to another format. Values are
+    /// read back with this database's codec and keep their key flags and
+    /// expiry. Fails if there already is a database at `new_prefix`.
+    pub fn migrate_codec<C2: Codec>(&self, new_prefix: &str) -> Result<OnDisk<K, V, C2>> {
+        if !data_file_ids(new_prefix)?.is_empty() {
+            bail!("{} already holds a database", new_prefix);
+        }
+        let mut migrated = OnDisk::<K, V, C2>::open(new_prefix)?;
+        migrated.batched(|migrated| {
+            for key in self.keys_owned()? {
+                let Some(value) = self.get(&key)? else {
+                    continue;
+                };
+                let expires_at = match self.write_buffer.get(&key) {
+                    Some(_) => 0,
+                    None => self.lookup(&key)?.map_or(0, |entry| entry.expires_at),
+                };
+                let key_flags = self.flags_of(&key)?.unwrap_or(0);
+                migrated.write_value(key, value, key_flags, expires_at)?;
+            }
+            Ok(())
+        })?;
+        Ok(migrated)
+    }
+
+    /// Writes `value` under `key` along with caller-defined `flags`, such as
+    /// marking the key pinned. The flags are kept in the record header and
+    /// follow the record through compactions. A plain `put` clears them.
+    pub fn put_with_flags(&mut self, key: K, value: V, flags: u32) -> Result<()> {
+        self.write_value(key, value, flags, 0)?;
+        Ok(())
+    }
+
+    /// Writes `value` under `key` to be read back only for the next `ttl`.
+    /// Once it expires `get` returns `None`, and the next `prune` drops it;
+    /// until then it still counts towards `len` and shows in `keys`.
+    pub fn put_with_ttl(&mut self, key: K, value: V, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64).max(1);
+        self.write_value(key, value, 0, expires_at)?;
+        Ok(())
+    }
+
+    /// Writes `new` under `key` only if the value there, as `get` reads it,
+    /// is `expected`, with `None` meaning the key is missing. Returns whether
+    /// it wrote.
+    pub fn compare_and_swap(&mut self, key: &K, expected: Option<&V>, new: V) -> Result<bool>
+    where
+        V: PartialEq,
+    {
+        if self.get(key)?.as_ref() != expected {
+            return Ok(false);
+        }
+        self.write_value(key.clone(), new, 0, 0)?;
+        Ok(true)
+    }
+
+    /// Returns the value under `key`, first writing the one `f` makes if the
+    /// key is missing.
+    pub fn get_or_insert_with(&mut self, key: K, f: impl FnOnce() -> V) -> Result<V> {
+        match self.get(&key)? {
+            Some(value) => Ok(value),
+            None => self.write_value(key, f(), 0, 0),
+        }
+    }
+
+    /// Reads the value under `key` as a `T` instead of a `V`, decoded with the
+    /// database's codec. With a codec that describes its data, like JSON, `T`
+    /// can be a generic value type. With the default bincode, which doesn't,
+    /// `T` must encode the same way `V` does, such as a tuple with the fields
+    /// of a struct. The read middleware is not applied.
+    pub fn get_as<T: DeserializeOwned>(&self, key: &K) -> Result<Option<T>> {
+        let key = &*self.normalize_key(key);
+        if let Some((value_buf, ..)) = self.write_buffer.get(key) {
+            return Ok(Some(C::deserialize(value_buf)?));
+        }
+        match self.lookup(key)? {
+            Some(entry) if !entry.is_expired(now_millis()) => {
+                Ok(Some(C::deserialize(&self.read_value_bytes(&entry)?)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads the values under `keys`, in the same order. The values on disk
+    /// are read a file at a time, each file opened once and read front to
+    /// back.
+    pub fn get_many(&self, keys: &[K]) -> Result<Vec<Option<V>>> {
+        let now = now_millis();
+        let mut values = Vec::with_capacity(keys.len());
+        let mut by_file: BTreeMap<u64, Vec<(usize, Entry)>> = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            let key = &*self.normalize_key(key);
+            self.touch(key);
+            if let Some((value_buf, ..)) = self.write_buffer.get(key) {
+                values.push(Some(self.apply_on_read(C::deserialize(value_buf)?)));
+                continue;
+            }
+            if let Some(entry) = self.lookup(key)?.filter(|entry| !entry.is_expired(now)) {
+                by_file
+                    .entry(entry.file_id)
+                    .or_default()
+                    .push((i, entry.into_owned()));
+            }
+            values.push(None);
+        }
+
+        for (file_id, mut entries) in by_file {
+            entries.sort_by_key(|(_, entry)| entry.slot.start);
+            let records = self.with_open_file(file_id, |file| {
+                entries
+                    .iter()
+                    .map(|(_, entry)| {
+                        let mut record = vec![0u8; (entry.slot.end - entry.slot.start) as usize];
+                        file.seek(SeekFrom::Start(entry.slot.start))?;
+                        file.read_exact(&mut record)?;
+                        Ok(record)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })?;
+            self.disk_reads
+                .fetch_add(records.len() as u64, Ordering::Relaxed);
+            for ((i, entry), record) in entries.iter().zip(records) {
+                let stored = self.stored_bytes_of(entry, record)?;
+                let value = C::deserialize(&self.value_format().load(stored, entry.flags)?)?;
+                values[*i] = Some(self.apply_on_read(value));
+            }
+        }
+        Ok(values)
+    }
+
+    /// Like `get`, but rereads the record from its data file every time,
+    /// past the value cache, the read-ahead buffer and any memory map, so it
+    /// checks what is on disk now rather than a copy read earlier. A record
+    /// that fails its checksum is reported as a `ChecksumMismatch`, which can
+    /// be recovered with `downcast_ref`. A write still held by write
+    /// coalescing has no record yet and comes back as written.
+    pub fn get_verified(&self, key: &K) -> Result<Option<V>> {
+        let key = &*self.normalize_key(key);
+        if let Some((value_buf, ..)) = self.write_buffer.get(key) {
+            return Ok(Some(self.apply_on_read(C::deserialize(value_buf)?)));
+        }
+        let entry = match self.lookup(key)? {
+            Some(entry) if !entry.is_expired(now_millis()) => entry,
+            _ => return Ok(None),
+        };
+        let mut file = File::open(format!("{}.{}.db", self.prefix, entry.file_id))?;
+        file.seek(SeekFrom::Start(entry.slot.start))?;
+        let mut record = vec![0u8; (entry.slot.end - entry.slot.start) as usize];
+        file.read_exact(&mut record)?;
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+        let stored = self.stored_bytes_of(&entry, record)?;
+        let value = C::deserialize(&self.value_format().load(stored, entry.flags)?)?;
+        Ok(Some(self.apply_on_read(value)))
+    }
+
+    /// Returns the flags `key` was last written with.
+    pub fn flags_of(&self, key: &K) -> Result<Option<u32>> {
+        let key = &*self.normalize_key(key);
+        if let Some((_, flags, _)) = self.write_buffer.get(key) {
+            return Ok(Some(*flags));
+        }
+        Ok(self.lookup(key)?.map(|entry| entry.key_flags))
+    }
+
+    /// Returns when `key` was last put, in milliseconds since the Unix epoch.
+    pub fn timestamp(&self, key: &K) -> Result<Option<u64>> {
+        let key = &*self.normalize_key(key);
+        if let Some((_, _, timestamp)) = self.write_buffer.get(key) {
+            return Ok(Some(*timestamp));
+        }
+        Ok(self.lookup(key)?.map(|entry| entry.timestamp))
+    }
+
+    /// Returns the length of the value bytes stored under `key`, after any
+    /// compression, from the in-memory index. Only a key whose entry
+    /// `set_key_dir_limit` has spilled needs a read, of the spill file, and
+    /// that read failing is why this returns a `Result`.
+    pub fn value_len(&self, key: &K) -> Result<Option<usize>> {
+        let key = &*self.normalize_key(key);
+        if let Some((value_buf, ..)) = self.write_buffer.get(key) {
+            return Ok(Some(value_buf.len()));
+        }
+        Ok(self.lookup(key)?.map(|entry| entry.value_len))
+    }
+
+    /// Returns the total length of the live values as stored, leaving out
+    /// record headers, keys and dead records.
+    pub fn live_value_bytes(&self) -> Result<u64> {
+        let spilled = self.spilled_entries()?;
+        Ok(self
+            .key_dir
+            .iter()
+            .chain(spilled.iter().map(|(key, entry)| (key, entry)))
+            .map(|(key, entry)| match self.write_buffer.get(key) {
+                Some((value_buf, ..)) => value_buf.len() as u64,
+                None => entry.value_len as u64,
+            })
+            .sum())
+    }
+
+    /// Returns the keys put since the last `sync` or `flush` whose values
+    /// could still be lost in a crash, in order: those in files not fsynced
+    /// since, reused space in older files included, and those held back by
+    /// write coalescing or strict durability. Keys spilled by
+    /// `set_key_dir_limit` count too, which takes reading the spill file.
+    pub fn unsynced_keys(&self) -> Result<Vec<K>> {
+        let unsynced = |entry: &Entry| {
+            entry.seq >= self.synced_seq && self.unsynced_files.contains(&entry.slot.file_id)
+        };
+        let mut keys: BTreeSet<K> = self
+            .spilled_entries()?
+            .into_iter()
+            .filter(|(_, entry)| unsynced(entry))
+            .map(|(key, _)| key)
+            .collect();
+        keys.extend(
+            self.key_dir
+                .iter()
+                .filter(|(_, entry)| unsynced(entry))
+                .map(|(key, _)| key.clone()),
+        );
+        keys.extend(self.write_buffer.keys().cloned());
+        keys.extend(
+            self.staged
+                .iter()
+                .filter(|(_, entry)| entry.is_some())
+                .map(|(key, _)| key.clone()),
+        );
+        Ok(keys.into_iter().collect())
+    }
+
+    /// Syncs the database and marks it closed cleanly, returning any error on
+    /// the way. Dropping the database does the same but has to swallow the
+    /// errors.
+    pub fn close(mut self) -> Result<()> {
+        self.closed = true;
+        if self.read_only {
+            return Ok(());
+        }
+        self.sync()?;
+        fs::write(format!("{}.clean", self.prefix), [])?;
+        Ok(())
+    }
+
+    /// Returns whether any put or delete since the last `sync` or `flush`
+    /// could still be lost in a crash.
+    pub fn has_unsynced_writes(&self) -> bool {
+        !self.unsynced_files.is_empty() || !self.write_buffer.is_empty() || !self.staged.is_empty()
+    }
+
+    /// When enabled, `prune` writes each distinct value once and points every
+    /// key holding those bytes at the same record. The other keys get small
+    /// alias records next to it, so the sharing survives reopening.
+    pub fn set_dedup_on_prune(&mut self, dedup: bool) {
+        self.dedup_on_prune = dedup;
+    }
+
+    /// Once a single key has been overwritten more than `threshold` times, the
+    /// next `sync` rewrites the active file of that moment to drop its dead
+    /// records, so no single put pays for the rewrite. `None` disables it.
+    pub fn set_hot_key_threshold(&mut self, threshold: Option<u64>) {
+        self.hot_key_threshold = threshold;
+    }
+
+    /// Runs `prune` after a put or delete once the bytes held by dead records
+    /// pass `threshold` times the bytes held by live ones. `None`, the
+    /// default, leaves pruning to the caller, and so does a threshold of 1.0
+    /// or more.
+    pub fn set_auto_compact_threshold(&mut self, threshold: Option<f64>) {
+        self.auto_compact_threshold = threshold.filter(|threshold| *threshold < 1.0);
+    }
+
+    /// Prunes if the auto compaction threshold has been crossed.
+    fn maybe_auto_compact(&mut self) -> Result<()> {
+        let Some(threshold) = self.auto_compact_threshold else {
+            return Ok(());
+        };
+        // batches check once at their end
+        if self.defer_flush || self.compacting_through.is_some() || self.snapshots_open() {
+            return Ok(());
+        }
+        if self.free_slots.total_len as f64 > threshold * self.live_bytes as f64 {
+            self.prune()?;
+        }
+        Ok(())
+    }
+
+    /// Returns how many data files the database has on disk next to how many
+    /// `recommended_file_count` suggests for their combined size.
+    pub fn file_counts(&self, max_file_size: u64) -> Result<(u64, u64)> {
+        let file_ids = data_file_ids(&self.prefix)?;
+        let mut total_bytes = 0;
+        for file_id in &file_ids {
+            total_bytes += fs::metadata(format!("{}.{}.db", self.prefix, file_id))?.len();
+        }
+        Ok((
+            file_ids.len() as u64,
+            recommended_file_count(total_bytes, max_file_size),
+        ))
+    }
+
+    /// Lists the keys deleted since the last `prune`, whose tombstones and
+    /// older records it would drop. Keys written again since are left out.
+    pub fn deleted_keys(&self) -> Result<Vec<&K>> {
+        // overwritten keys pass through the delete map too
+        let mut deleted = vec![];
+        for key in self.delete_map.keys() {
+            if self.lookup(key)?.is_none() {
+                deleted.push(key);
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Counts keys, files and dead space from the index and file sizes,
+    /// without reading any records.
+    pub fn stats(&self) -> Result<DbStats> {
+        let file_ids = data_file_ids(&self.prefix)?;
+        let mut disk_bytes = 0;
+        for file_id in &file_ids {
+            disk_bytes += fs::metadata(format!("{}.{}.db", self.prefix, file_id))?.len();
+        }
+        Ok(DbStats {
+            live_keys: self.len(),
+            tombstones: self.deleted_keys()?.len(),
+            files: file_ids.len(),
+            disk_bytes,
+            reclaimable_bytes: self.free_slots.total_len,
+        })
+    }
+
+    /// When enabled, `prune` stores the values it rewrites into older files
+    /// compressed. The active file is left uncompressed so writes stay cheap,
+    /// and reads decompress records flagged as compressed.
+    pub fn set_compress_on_prune(&mut self, compress: bool) {
+        self.compress_on_prune = compress;
+    }
+
+    /// Compresses every value written from now on with `compressor`, which
+    /// also reads back values already compressed. `None`, the default,
+    /// stores new values as they are.
+    pub fn set_compressor(&mut self, compressor: Option<Box<dyn Compressor>>) {
+        self.compressor = compressor;
+    }
+
+    /// Encrypts every value written from now on with `cipher`, which also
+    /// decrypts the values already encrypted. `prune` encrypts the rest.
+    #[cfg(feature = "encryption")]
+    pub fn set_cipher(&mut self, cipher: Option<Box<dyn Cipher>>) {
+        self.cipher = cipher;
+    }
+
+    /// How values are stored: with the compressor set, deflate otherwise,
+    /// and with the cipher if there is one.
+    fn value_format(&self) -> ValueFormat<'_> {
+        ValueFormat {
+            compressor: self.compressor.as_deref().unwrap_or(&Deflate),
+            #[cfg(feature = "encryption")]
+            cipher: self.cipher.as_deref(),
+        }
+    }
+
+    /// Lets `prune` rewrite up to `threads` data files at once. Pruning with
+    /// deduplication compares values across files, so it stays on one thread.
+    pub fn set_compaction_threads(&mut self, threads: usize) {
+        self.compaction_threads = threads.max(1);
+    }
+
+    /// Holds overwrites of existing keys in memory, keeping only the latest
+    /// value per key, until `limit` keys are buffered or the database is
+    /// synced or compacted. Writing a hot key many times then leaves one
+    /// record instead of one per write. Reads and snapshots see buffered
+    /// values, while methods about records on disk, like `byte_range`, see
+    /// the last value written there. `None` writes every put straight through.
+    pub fn set_write_coalescing(&mut self, limit: Option<usize>) -> Result<()> {
+        self.write_buffer_limit = limit;
+        self.flush_writes()
+    }
+
+    /// Caps the bytes taken by live records at `max`. A write that would go
+    /// past it first deletes the least recently read or written keys, turning
+    /// the database into a disk-backed LRU cache. Space of evicted records is
+    /// reused by later writes or reclaimed by `prune`. `None` lifts the cap.
+    /// Eviction looks at every key, so this can't be combined with
+    /// `set_key_dir_limit`.
+    pub fn set_max_total_bytes(&mut self, max: Option<u64>) -> Result<()> {
+        if max.is_some() && self.key_dir_limit.is_some() {
+            bail!("a size budget needs the whole key index in memory");
+        }
+        self.max_total_bytes = max;
+        Ok(())
+    }
+
+    /// When enabled, writes and deletes only show in the index once the
+    /// active file has been fsynced, by `sync`, `flush` or an operation that
+    /// rewrites files, so reads never see data that a crash could still lose.
+    /// Until then reads return the previous value. Turning it off makes
+    /// pending writes visible right away.
+    pub fn set_strict_durability(&mut self, enabled: bool) -> Result<()> {
+        self.strict_durability = enabled;
+        if !enabled {
+            self.promote_staged()?;
+        }
+        Ok(())
+    }
+
+    /// Starts a new data file whenever appending a record would take the
+    /// active one past `max` bytes. `None` lets the active file grow until the
+    /// next `sync`.
+    pub fn set_max_file_size(&mut self, max: Option<u64>) {
+        self.max_file_size = max;
+    }
+
+    /// When enabled, appended and rewritten records are preceded by a fixed
+    /// separator, so scans of a data file can skip past a corrupt record to
+    /// the ones after it instead of giving up on the rest of the file.
+    /// Records written into reused space go without one.
+    pub fn set_record_separators(&mut self, enabled: bool) {
+        self.record_separators = enabled;
+    }
+
+    /// Sets the checksum that records written from now on are checked with.
+    /// Records already written keep theirs, and the flags of each record say
+    /// which one it is, so reads never need to be told.
+    pub fn set_checksum(&mut self, checksum: Checksum) {
+        self.checksum = checksum;
+    }
+
+    /// Keep at most `limit` entries of the key index in memory. Past that, the
+    /// least recently written entries outside the active file move to a
+    /// sorted spill file that point lookups fall back to, at the cost of a
+    /// disk read. `None` keeps the whole index in memory.
+    pub fn set_key_dir_limit(&mut self, limit: Option<usize>) -> Result<()> {
+        if limit.is_some() {
+            // spilling writes a file of its own
+            self.check_writable()?;
+        }
+        if limit.is_some() && self.max_total_bytes.is_some() {
+            bail!("a size budget needs the whole key index in memory");
+        }
+        self.key_dir_limit = limit;
+        self.spill_cold_entries()
+    }
+
+    /// Finds the entry for `key`, in memory or in the spill file.
+    fn lookup(&self, key: &K) -> Result<Option<Cow<'_, Entry>>> {
+        if let Some(entry) = self.key_dir.get(key) {
+            return Ok(Some(Cow::Borrowed(entry)));
+        }
+        if self.spill_shadowed.contains(key) {
+            return Ok(None);
+        }
+        let block = self.spill_index.partition_point(|(first, _)| first <= key);
+        let Some(block) = block.checked_sub(1) else {
+            return Ok(None);
+        };
+        Ok(self
+            .spill_block(block)?
+            .into_iter()
+            .find(|(spilled_key, _)| spilled_key == key)
+            .map(|(_, entry)| Cow::Owned(entry)))
+    }
+
+    /// Reads the entries of the `block`th block of the spill file, shadowed
+    /// ones included.
+    fn spill_block(&self, block: usize) -> Result<Vec<(K, Entry)>> {
+        let mut reader = BufReader::new(File::open(self.spill_path())?);
+        reader.seek(SeekFrom::Start(self.spill_index[block].1))?;
+        let mut entries = Vec::with_capacity(SPILL_BLOCK_LEN);
+        for _ in 0..SPILL_BLOCK_LEN {
+            match bincode::deserialize_from(&mut reader) {
+                Ok(record) => entries.push(record),
+                // the last block may be short
+                Err(e) => match *e {
+                    bincode::ErrorKind::Io(ref io)
+                        if io.kind() == std::io::ErrorKind::UnexpectedEof =>
+                    {
+                        break
+                    }
+                    _ => return Err(e.into()),
+                },
+            }
+        }
+        Ok(entries)
+    }
+
+    fn spill_path(&self) -> String {
+        format!("{}.spill.db", self.prefix)
+    }
+
+    fn hint_path(&self, file_id: u64) -> String {
+        format!("{}.{}.hint.db", self.prefix, file_id)
+    }
+
+    /// Lists the entries of data file `file_id` in a hint file, so `open`
+    /// can index the file without reading it. Only written for files that
+    /// nothing else writes to afterwards. Hints need no tombstones: they are
+    /// only written by `prune`, whose files hold live records alone, and a
+    /// later delete lands in a file without a hint, which `open` scans and
+    /// whose tombstone outranks the hinted record by sequence number.
+    fn write_hint(&mut self, file_id: u64, entries: &[(&K, &Entry)]) -> Result<()> {
+        let temp_path = format!("{}.tmp", self.hint_path(file_id));
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        bincode::serialize_into(&mut writer, entries)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(temp_path, self.hint_path(file_id))?;
+        self.hinted_files.insert(file_id);
+        Ok(())
+    }
+
+    /// Drops the hint file of `file_id`, before the data file changes.
+    fn remove_hint(&mut self, file_id: u64) -> Result<()> {
+        if self.hinted_files.remove(&file_id) {
+            fs::remove_file(self.hint_path(file_id))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the hint file of `file_id`, if it has a usable one.
+    fn read_hint(&self, file_id: u64) -> Result<Option<Vec<(K, Entry)>>> {
+        let bytes = match fs::read(self.hint_path(file_id)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match bincode::deserialize::<Vec<(K, Entry)>>(&bytes) {
+            Ok(entries) => Ok(Some(entries)),
+            // torn or stale, so the data file is scanned instead
+            Err(_) => {
+                if !self.read_only {
+                    fs::remove_file(self.hint_path(file_id))?;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reads every spilled entry that has not been moved back into memory.
+    fn spilled_entries(&self) -> Result<Vec<(K, Entry)>> {
+        if self.spill_index.is_empty() {
+            return Ok(vec![]);
+        }
+        let file = File::open(self.spill_path())?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut entries = vec![];
+        while reader.stream_position()? < len {
+            let (key, entry): (K, Entry) = bincode::deserialize_from(&mut reader)?;
+            if !self.spill_shadowed.contains(&key) {
+                entries.push((key, entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Moves a spilled entry back into `key_dir` before it is changed.
+    fn unspill(&mut self, key: &K) -> Result<()> {
+        if self.key_dir.contains_key(key) || self.spill_shadowed.contains(key) {
+            return Ok(());
+        }
+        if let Some(entry) = self.lookup(key)?.map(Cow::into_owned) {
+            self.key_dir.insert(key.clone(), entry);
+            self.spill_shadowed.insert(key.clone());
+        }
+        Ok(())
+    }
+
+    /// Brings the whole index back into memory, for operations that walk
+    /// every key.
+    fn load_spilled(&mut self) -> Result<()> {
+        for (key, entry) in self.spilled_entries()? {
+            self.key_dir.entry(key).or_insert(entry);
+        }
+        self.discard_spill()
+    }
+
+    /// Rebuilds the index from the data files. Each key takes its record with
+    /// the highest sequence number, as records can land in older files through
+    /// reused space, and keys whose latest record is a tombstone stay deleted.
+    /// The space of older records is handed out again. Files with a hint file
+    /// are indexed from it without being read.
+    fn recover(&mut self) -> Result<()> {
+        // the index is all in memory again, so a leftover spill file is stale
+        let spill_path = self.spill_path();
+        if !self.read_only && fs::exists(&spill_path)? {
+            fs::remove_file(spill_path)?;
+        }
+
+        let file_ids = data_file_ids(&self.prefix)?;
+        let mut latest: BTreeMap<K, (Entry, bool)> = BTreeMap::new();
+        let mut dead = BTreeSet::new();
+        // records by sequence number, dead ones too, for aliases to point at
+        let mut by_seq: BTreeMap<u64, Entry> = BTreeMap::new();
+        let mut aliases: BTreeMap<u64, u64> = BTreeMap::new();
+        // transactions by the sequence number of their first record, up to
+        // that of their commit record
+        let mut commits: BTreeMap<u64, u64> = BTreeMap::new();
+        let mut batched = BTreeSet::new();
+        let mut records = vec![];
+        for &file_id in &file_ids {
+            if let Some(hinted) = self.read_hint(file_id)? {
+                // hinted files hold no tombstones, and list aliased keys with
+                // the entry they share
+                for (key, entry) in hinted {
+                    by_seq.insert(entry.seq, entry.clone());
+                    records.push((key, entry, false));
+                }
+                self.hinted_files.insert(file_id);
+            } else {
+                let path = format!("{}.{}.db", self.prefix, file_id);
+                let mut scanned = vec![];
+                let mut error = scan_file(&path, |record| scanned.push(record))?;
+                if error.is_some() && file_ids.last() == Some(&file_id) {
+                    if let Some(valid_len) = torn_tail(&path)? {
+                        // a crash cut the last append short, so it never
+                        // finished and can go; read-only, it may also be one
+                        // still being written
+                        if !self.read_only {
+                            let file = OpenOptions::new().write(true).open(&path)?;
+                            file.set_len(valid_len)?;
+                            file.sync_all()?;
+                        }
+                        scanned.retain(|record| record.end <= valid_len);
+                        error = None;
+                    }
+                }
+                if let Some(e) = error {
+                    return Err(e.context(format!("failed to recover {}", path)));
+                }
+                for record in scanned {
+                    if record.flags & RECORD_COMMIT != 0 {
+                        let first_seq = record.value.as_slice().try_into().map_err(|_| {
+                            anyhow!("bad commit record at offset {} of {}", record.start, path)
+                        })?;
+                        commits.insert(u64::from_be_bytes(first_seq), record.seq);
+                        self.next_seq = self.next_seq.max(record.seq + 1);
+                        continue;
+                    }
+                    if record.flags & RECORD_BATCH != 0 {
+                        batched.insert(record.seq);
+                    }
+                    let entry = Entry {
+                        file_id,
+                        value_len: record.value.len(),
+                        flags: record.flags & !RECORD_BATCH,
+                        key_flags: record.key_flags,
+                        seq: record.seq,
+                        timestamp: record.timestamp,
+                        expires_at: record.expires_at,
+                        slot: Slot {
+                            file_id,
+                            start: record.start,
+                            end: record.end,
+                        },
+                    };
+                    let tombstone = record.flags & RECORD_TOMBSTONE != 0;
+                    if record.flags & RECORD_ALIAS != 0 {
+                        let target_seq = record.value.as_slice().try_into().map_err(|_| {
+                            anyhow!("bad alias record at offset {} of {}", record.start, path)
+                        })?;
+                        aliases.insert(record.seq, u64::from_be_bytes(target_seq));
+                    } else if !tombstone {
+                        by_seq.insert(record.seq, entry.clone());
+                    }
+                    records.push((C::deserialize(&record.key)?, entry, tombstone));
+                }
+            }
+        }
+        for (key, entry, tombstone) in records {
+            self.next_seq = self.next_seq.max(entry.seq + 1);
+            // a transaction cut short by a crash never happened
+            let committed = !batched.contains(&entry.seq)
+                || commits
+                    .range(..=entry.seq)
+                    .next_back()
+                    .is_some_and(|(_, &commit_seq)| entry.seq < commit_seq);
+            if !committed {
+                dead.insert(entry.slot);
+                continue;
+            }
+            // tombstones are never handed out, as they have to outlive
+            // every older record of their key
+            match latest.get(&key) {
+                Some((newer, _)) if newer.seq > entry.seq => {
+                    if !tombstone {
+                        dead.insert(entry.slot);
+                    }
+                }
+                _ => {
+                    if let Some((older, false)) = latest.insert(key, (entry, tombstone)) {
+                        dead.insert(older.slot);
+                    }
+                }
+            }
+        }
+
+        for (key, (entry, tombstone)) in latest {
+            if tombstone {
+                continue;
+            }
+            let entry = match aliases.get(&entry.seq) {
+                // the alias record itself stays put for as long as the key
+                // is live, so reopening finds it again
+                Some(target_seq) => {
+                    let target = by_seq.get(target_seq).ok_or_else(|| {
+                        anyhow!(
+                            "alias record in file {} points at a missing record",
+                            entry.file_id
+                        )
+                    })?;
+                    Entry {
+                        file_id: target.file_id,
+                        value_len: target.value_len,
+                        flags: target.flags,
+                        slot: target.slot.clone(),
+                        ..entry
+                    }
+                }
+                None => entry,
+            };
+            self.live_bytes += entry.slot.end - entry.slot.start;
+            self.key_dir.insert(key, entry);
+        }
+        // hint files keep the keys sharing a deduplicated record, whose
+        // space stays taken while any of them is left
+        let mut live_slots: BTreeMap<Slot, usize> = BTreeMap::new();
+        for entry in self.key_dir.values() {
+            *live_slots.entry(entry.slot.clone()).or_default() += 1;
+        }
+        for slot in dead {
+            if !live_slots.contains_key(&slot) {
+                self.free_slots.insert(slot);
+            }
+        }
+        live_slots.retain(|_, refs| *refs > 1);
+        self.slot_refs = live_slots;
+        if let Some(&file_id) = file_ids.last() {
+            self.file_id = file_id;
+            self.file_position = fs::metadata(format!("{}.{}.db", self.prefix, file_id))?.len();
+        }
+        self.synced_seq = self.next_seq;
+        Ok(())
+    }
+
+    fn discard_spill(&mut self) -> Result<()> {
+        if !self.spill_index.is_empty() {
+            fs::remove_file(self.spill_path())?;
+        }
+        self.spill_index = Vec::new();
+        self.spill_len = 0;
+        self.spill_shadowed = BTreeSet::new();
+        Ok(())
+    }
+
+    /// Spills down to half the limit at once, so the spill file is not
+    /// rewritten on every put.
+    fn spill_cold_entries(&mut self) -> Result<()> {
+        let Some(limit) = self.key_dir_limit else {
+            return Ok(());
+        };
+        // a compaction swaps in entries for the keys it snapshotted, which
+        // must still be in memory when it finishes
+        if self.key_dir.len() <= limit || self.compacting_through.is_some() {
+            return Ok(());
+        }
+        let mut cold: Vec<_> = self
+            .key_dir
+            .iter()
+            .filter(|(_, entry)| entry.file_id != self.file_id)
+            .map(|(key, entry)| (entry.seq, key.clone()))
+            .collect();
+        cold.sort();
+        cold.truncate(self.key_dir.len() - limit / 2);
+        if cold.is_empty() {
+            return Ok(());
+        }
+
+        let mut spilled: BTreeMap<K, Entry> = self.spilled_entries()?.into_iter().collect();
+        for (_, key) in cold {
+            if let Some(entry) = self.key_dir.remove(&key) {
+                spilled.insert(key, entry);
+            }
+        }
+
+        let temp_path = format!("{}.tmp", self.spill_path());
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        let mut spill_index = vec![];
+        for (i, record) in spilled.iter().enumerate() {
+            if i % SPILL_BLOCK_LEN == 0 {
+                spill_index.push((record.0.clone(), writer.stream_position()?));
+            }
+            bincode::serialize_into(&mut writer, &record)?;
+        }
+        writer.flush()?;
+        fs::rename(temp_path, self.spill_path())?;
+
+        self.spill_index = spill_index;
+        self.spill_len = spilled.len();
+        self.spill_shadowed = BTreeSet::new();
+        Ok(())
+    }
+
+    /// Serve reads by slicing memory-mapped data files instead of seeking and
+    /// reading. Writes still go through regular file IO.
+    #[cfg(feature = "mmap")]
+    pub fn set_mmap(&mut self, enabled: bool) {
+        self.use_mmap = enabled;
+        self.release_files();
+    }
+
+    /// Keep at most `max` data files open for reads, closing the least
+    /// recently used one past that. Zero opens a file for every read.
+    pub fn set_max_open_files(&mut self, max: usize) {
+        self.max_open_files = max;
+        let mut open_files = self
+            .open_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let excess = open_files.len().saturating_sub(max);
+        open_files.drain(..excess);
+    }
+
+    /// Read at least `bytes` from a data file at a time, keeping what follows
+    /// the requested value to serve the next reads. This makes scans over
+    /// records laid out in key order, such as right after a `prune`, need far
+    /// fewer reads. Zero reads exactly one value at a time.
+    pub fn set_read_ahead(&mut self, bytes: usize) {
+        self.read_ahead = bytes;
+        self.read_ahead_buf = Mutex::default();
+    }
+
+    /// Returns how many reads have gone to the data files so far.
+    pub fn disk_reads(&self) -> u64 {
+        self.disk_reads.load(Ordering::Relaxed)
+    }
+
+    /// Drops every cached handle and mapping; needed whenever a data file is
+    /// replaced on disk.
+    fn release_files(&mut self) {
+        self.active_writer = None;
+        *self
+            .read_ahead_buf
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        self.open_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        #[cfg(feature = "mmap")]
+        self.mmaps
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Runs `f` against a read handle for `file_id`, reusing an open one when
+    /// there is one.
+    fn with_open_file<T>(&self, file_id: u64, f: impl FnOnce(&mut File) -> Result<T>) -> Result<T> {
+        let mut open_files = self
+            .open_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // most recently used handles live at the back
+        let mut file = match open_files.iter().position(|(f_id, _)| *f_id == file_id) {
+            Some(i) => open_files.remove(i).1,
+            None => self.get_file_by_id(file_id)?,
+        };
+        let result = f(&mut file);
+        if self.max_open_files > 0 {
+            if open_files.len() >= self.max_open_files {
+                open_files.remove(0);
+            }
+            open_files.push((file_id, file));
+        }
+        result
+    }
+
+    #[cfg(feature = "mmap")]
+    fn read_mapped_bytes(&self, file_id: u64, pos: u64, len: usize) -> Result<Vec<u8>> {
+        let file_id = &file_id;
+        let value_pos = pos as usize;
+        let value_end = value_pos + len;
+
+        let mut mmaps = self.mmaps.lock().unwrap_or_else(PoisonError::into_inner);
+        // the active file keeps growing, so remap when a read runs past the
+        // end of the current mapping
+        if mmaps.get(file_id).is_none_or(|mmap| mmap.len() < value_end) {
+            let file = self.get_file_by_id(*file_id)?;
+            // SAFETY: data files are only changed by this process, and every
+            // path that replaces one on disk drops the mappings first.
+            let mmap = unsafe { Mmap::map(&file)? };
+            mmaps.insert(*file_id, mmap);
+        }
+        Ok(mmaps[file_id][value_pos..value_end].to_vec())
+    }
+
+    /// Loads the value for `key` into the in-memory cache so `get_cow` can
+    /// hand out references to it. Returns whether the key exists.
+    pub fn cache_value(&mut self, key: &K) -> Result<bool> {
+        let Some(value) = self.get(key)? else {
+            return Ok(false);
+        };
+        let key = self.normalize_key(key).into_owned();
+        self.value_cache.insert(key, value);
+        Ok(true)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.flush_active_writer()?;
+        self.file_id += 1;
+        let db_name = format!("{}.{}.db", self.prefix, self.file_id);
+        let _ = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(db_name)?;
+        self.file_position = 0;
+        self.is_dirty = false;
+        self.active_writer = None;
+        Ok(())
+    }
+
+    /// Rewrites the files hot keys left dead records in, unless a snapshot or
+    /// compaction needs their records where they are, in which case the next
+    /// `sync` tries again.
+    fn compact_hot_files(&mut self) -> Result<()> {
+        if self.compacting_through.is_some() || self.snapshots_open() {
+            return Ok(());
+        }
+        for file_id in std::mem::take(&mut self.hot_files) {
+            if fs::exists(format!("{}.{}.db", self.prefix, file_id))? {
+                self.compact_file(file_id)?;
+                self.unsynced_files.insert(file_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn record_overwrite(&mut self, key: &K) -> bool {
+        let Some(threshold) = self.hot_key_threshold else {
+            return false;
+        };
+        let count = self.overwrite_counts.entry(key.clone()).or_default();
+        *count += 1;
+        if *count > threshold {
+            self.overwrite_counts.remove(key);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn flush_active_writer(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.active_writer {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn get_file_by_id(&self, file_id: u64) -> Result<File> {
+        let file_name = format!("{}.{}.db", self.prefix, file_id);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!self.read_only)
+            .open(file_name)?;
+        Ok(file)
+    }
+
+    fn get_tempfile_by_id(&self, file_id: u64) -> Result<File> {
+        let file_name = format!("{}.{}.temp.db", self.prefix, file_id);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(file_name)?;
+        Ok(file)
+    }
+
+    fn curr_file(&self) -> Result<File> {
+        let file_name = format!("{}.{}.db", self.prefix, self.file_id);
+        let file = OpenOptions::new().read(true).write(true).open(file_name)?;
+        Ok(file)
+    }
+
+    /// Reads the serialized value of `entry`, decrypting and decompressing it
+    /// if needed.
+    fn read_value_bytes(&self, entry: &Entry) -> Result<Vec<u8>> {
+        self.value_format()
+            .load(self.read_stored_bytes(entry)?, entry.flags)
+    }
+
+    /// Returns a reader over the serialized value under `key`, streamed from
+    /// its data file rather than read into memory. The record's checksum is
+    /// not checked. Compressed and encrypted values, and writes still
+    /// buffered, are decoded in memory first.
+    pub fn get_reader(&self, key: &K) -> Result<Option<impl Read>> {
+        let key = &*self.normalize_key(key);
+        self.touch(key);
+        if let Some((value_buf, ..)) = self.write_buffer.get(key) {
+            return Ok(Some(
+                Box::new(std::io::Cursor::new(value_buf.clone())) as Box<dyn Read>
+            ));
+        }
+        let entry = match self.lookup(key)? {
+            Some(entry) if !entry.is_expired(now_millis()) => entry,
+            _ => return Ok(None),
+        };
+        if entry.flags & (RECORD_COMPRESSED | RECORD_ENCRYPTED) != 0 {
+            return Ok(Some(Box::new(std::io::Cursor::new(
+                self.read_value_bytes(&entry)?,
+            ))));
+        }
+        let mut file = File::open(format!("{}.{}.db", self.prefix, entry.file_id))?;
+        file.seek(SeekFrom::Start(compute_value_pos(
+            entry.value_len,
+            &entry.slot,
+        )))?;
+        Ok(Some(Box::new(file.take(entry.value_len as u64))))
+    }
+
+    /// Reads the value bytes of `entry` as they are on disk, after checking
+    /// the checksum of the record holding them.
+    fn read_stored_bytes(&self, entry: &Entry) -> Result<Vec<u8>> {
+        self.stored_bytes_of(entry, self.read_record_bytes(entry)?)
+    }
+
+    /// Takes the value bytes out of `record`, the record of `entry`, after
+    /// checking its checksum.
+    fn stored_bytes_of(&self, entry: &Entry, mut record: Vec<u8>) -> Result<Vec<u8>> {
+        let checksum = u32::from_be_bytes(record[..4].try_into()?);
+        if Checksum::of_flags(record[4])?.crc().checksum(&record[4..]) != checksum {
+            return Err(ChecksumMismatch {
+                file_id: entry.file_id,
+                offset: entry.slot.start,
+            }
+            .into());
+        }
+        Ok(record.split_off(record.len() - entry.value_len))
+    }
+
+    /// Reads the whole record of `entry`.
+    fn read_record_bytes(&self, entry: &Entry) -> Result<Vec<u8>> {
+        let record_len = (entry.slot.end - entry.slot.start) as usize;
+        #[cfg(feature = "mmap")]
+        if self.use_mmap {
+            return self.read_mapped_bytes(entry.file_id, entry.slot.start, record_len);
+        }
+
+        if self.read_ahead > 0 {
+            return self.read_ahead_bytes(entry.file_id, entry.slot.start, record_len);
+        }
+
+        let mut record = vec![0u8; record_len];
+        self.with_open_file(entry.file_id, |file| {
+            file.seek(SeekFrom::Start(entry.slot.start))?;
+            file.read_exact(&mut record)?;
+            Ok(())
+        })?;
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+        Ok(record)
+    }
+
+    /// Serves `len` bytes at `pos` from the read-ahead buffer, refilling it
+    /// from there onwards when it does not cover them.
+    fn read_ahead_bytes(&self, file_id: u64, pos: u64, len: usize) -> Result<Vec<u8>> {
+        let mut read_ahead_buf = self
+            .read_ahead_buf
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(buf) = read_ahead_buf.as_ref() {
+            let end = pos + len as u64;
+            if buf.file_id == file_id
+                && buf.start <= pos
+                && end <= buf.start + buf.bytes.len() as u64
+            {
+                let offset = (pos - buf.start) as usize;
+                return Ok(buf.bytes[offset..offset + len].to_vec());
+            }
+        }
+
+        let mut bytes = vec![];
+        self.with_open_file(file_id, |file| {
+            file.seek(SeekFrom::Start(pos))?;
+            file.take(self.read_ahead.max(len) as u64)
+                .read_to_end(&mut bytes)?;
+            Ok(())
+        })?;
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+        if bytes.len() < len {
+            bail!("value at offset {} of file {} is cut short", pos, file_id);
+        }
+        let value = bytes[..len].to_vec();
+        *read_ahead_buf = Some(ReadAheadBuf {
+            file_id,
+            start: pos,
+            bytes,
+        });
+        Ok(value)
+    }
+
+    fn read_value(&self, entry: &Entry) -> Result<V> {
+        C::deserialize(&self.read_value_bytes(entry)?)
+    }
+
+    /// Reads a value for handing out to callers, past the read middleware.
+    /// Records being moved around on disk go through `read_value` instead.
+    fn load_value(&self, entry: &Entry) -> Result<V> {
+        Ok(self.apply_on_read(self.read_value(entry)?))
+    }
+
+    /// Reads the value `put` is about to replace under `key`, as stored. Unlike
+    /// `get`, it counts as no access to the key and skips the read
+    /// middleware. A value that fails its checksum or can't be decoded reads
+    /// as `None`, so it can still be overwritten.
+    fn previous_value(&self, key: &K) -> Result<Option<V>> {
+        let key = &*self.normalize_key(key);
+        let value_bytes = match self.write_buffer.get(key) {
+            Some((value_buf, ..)) => value_buf.clone(),
+            None => match self.lookup(key)? {
+                Some(entry) if !entry.is_expired(now_millis()) => {
+                    match self.read_value_bytes(&entry) {
+                        Ok(value_bytes) => value_bytes,
+                        Err(e) if e.is::<ChecksumMismatch>() => return Ok(None),
+                        Err(e) => return Err(e),
+                    }
+                }
+                _ => return Ok(None),
+            },
+        };
+        Ok(C::deserialize(&value_bytes).ok())
+    }
+
+    fn normalize_key<'k>(&self, key: &'k K) -> Cow<'k, K> {
+        match &self.key_normalizer {
+            Some(normalize) => Cow::Owned(normalize(key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    fn apply_on_read(&self, value: V) -> V {
+        match &self.on_read {
+            Some(on_read) => on_read(value),
+            None => value,
+        }
+    }
+
+    /// Rewrites `file_id` keeping only the records `key_dir` still points at,
+    /// and forgets the free slots and tombstones that lived in it.
+    fn rewrite_file(&mut self, file_id: u64) -> Result<()> {
+        self.flush_active_writer()?;
+        let temp_file_path = format!("{}.{}.temp.db", self.prefix, file_id);
+        let file_path = format!("{}.{}.db", self.prefix, file_id);
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_file_path)?;
+
+        let mut moved: BTreeMap<Slot, Entry> = BTreeMap::new();
+        let mut new_entries = vec![];
+        let mut writers = TempWriters::default();
+        for (key, entry) in self
+            .key_dir
+            .iter()
+            .filter(|(_, entry)| entry.file_id == file_id)
+        {
+            if let Some(new_entry) = moved.get(&entry.slot) {
+                self.alias_to_file(&mut writers, key, new_entry.seq, entry, file_id)?;
+                let new_entry = Entry {
+                    seq: entry.seq,
+                    timestamp: entry.timestamp,
+                    ..new_entry.clone()
+                };
+                new_entries.push((key.clone(), new_entry));
+                continue;
+            }
+            let value = self.read_value(entry)?;
+            let compressed = entry.flags & RECORD_COMPRESSED != 0;
+            let new_entry = self.serialize_to_file(
+                &mut writers,
+                key,
+                value,
+                entry.seq,
+                entry.key_flags,
+                entry.timestamp,
+                entry.expires_at,
+                file_id,
+                compressed,
+            )?;
+            moved.insert(entry.slot.clone(), new_entry.clone());
+            new_entries.push((key.clone(), new_entry));
+        }
+
+        // tombstones are kept for keys still deleted, since older records of
+        // them may live on in other files, and commit records for the
+        // transaction records there
+        let mut tombstones = vec![];
+        let scanned = scan_file(&file_path, |record| {
+            if record.flags & (RECORD_TOMBSTONE | RECORD_COMMIT) != 0 {
+                tombstones.push(record);
+            }
+        })?;
+        if let Some(e) = scanned {
+            return Err(e);
+        }
+        for record in tombstones {
+            if record.flags & RECORD_TOMBSTONE != 0
+                && self.lookup(&C::deserialize(&record.key)?)?.is_some()
+            {
+                continue;
+            }
+            let record = encode_record(
+                self.checksum,
+                record.flags,
+                record.key_flags,
+                record.seq,
+                record.timestamp,
+                0,
+                &record.key,
+                &record.value,
+            );
+            self.append_to_temp(&mut writers, file_id, &record)?;
+        }
+        writers.flush()?;
+        drop(writers);
+
+        let file_position = self.get_tempfile_by_id(file_id)?.metadata()?.len();
+        self.release_files();
+        self.remove_hint(file_id)?;
+        fs::rename(temp_file_path, file_path)?;
+
+        for (key, entry) in new_entries {
+            self.link(key, entry);
+        }
+        let slot_refs: Vec<_> = moved
+            .iter()
+            .filter_map(|(old, new)| {
+                self.slot_refs
+                    .remove(old)
+                    .map(|refs| (new.slot.clone(), refs))
+            })
+            .collect();
+        self.slot_refs.extend(slot_refs);
+        self.free_slots.retain(|slot| slot.file_id != file_id);
+        self.deferred_slots.retain(|slot| slot.file_id != file_id);
+        self.delete_map.retain(|_, entry| entry.file_id != file_id);
+        let delete_map = &self.delete_map;
+        self.restorable.retain(|key| delete_map.contains_key(key));
+        if file_id == self.file_id {
+            self.file_position = file_position;
+        }
+        Ok(())
+    }
+
+    /// Writes `value` under `key` with the given key flags; the body of `put`.
+    /// Returns the value as written, after the write middleware.
+    fn write_value(&mut self, key: K, value: V, key_flags: u32, expires_at: u64) -> Result<V> {
+        self.check_writable()?;
+        let key = match &self.key_normalizer {
+            Some(normalize) => normalize(&key),
+            None => key,
+        };
+        self.unspill(&key)?;
+        let value = match &self.on_write {
+            Some(on_write) => on_write(value),
+            None => value,
+        };
+        let serialized_value = C::serialize(&value)?;
+        let timestamp = now_millis();
+
+        // only overwrites are held back, so the set of live keys is always
+        // what the index says
+        let buffered = self
+            .write_buffer_limit
+            .filter(|_| expires_at == 0 && self.key_dir.contains_key(&key));
+        if let Some(limit) = buffered {
+            self.value_cache.remove(&key);
+            self.write_buffer
+                .insert(key, (serialized_value, key_flags, timestamp));
+            if self.write_buffer.len() >= limit {
+                self.flush_writes()?;
+            }
+        } else {
+            self.write_serialized(key, serialized_value, key_flags, timestamp, expires_at)?;
+        }
+        self.maybe_auto_compact()?;
+        Ok(value)
+    }
+
+    /// Deletes the least recently used keys other than `key` until a record of
+    /// `len` bytes fits in the size budget.
+    fn evict_for(&mut self, key: &K, len: u64) -> Result<()> {
+        let Some(max_total_bytes) = self.max_total_bytes else {
+            return Ok(());
+        };
+        while self.live_bytes + len > max_total_bytes {
+            let access_times = self
+                .access_times
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner);
+            // keys not read or written since opening count as the coldest
+            let coldest = self
+                .key_dir
+                .iter()
+                .filter(|(candidate, _)| *candidate != key)
+                .min_by_key(|(candidate, _)| access_times.get(*candidate).copied().unwrap_or(0))
+                .map(|(candidate, _)| candidate.clone());
+            let Some(coldest) = coldest else {
+                break;
+            };
+            self.delete(&coldest)?;
+        }
+        Ok(())
+    }
+
+    /// Marks `key` as just used, when a size budget is set.
+    fn touch(&self, key: &K) {
+        if self.max_total_bytes.is_some() {
+            let time = self.access_clock.fetch_add(1, Ordering::Relaxed) + 1;
+            self.access_times
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(key.clone(), time);
+        }
+    }
+
+    /// Appends a record for `key` without making it visible, for
+    /// `promote_staged` to do once it is on disk for sure. The old record
+    /// stays readable until then, so no space is reused for it.
+    fn stage_write(
+        &mut self,
+        key: K,
+        serialized_value: Vec<u8>,
+        key_flags: u32,
+        timestamp: u64,
+        expires_at: u64,
+        flags: u8,
+    ) -> Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let record = encode_record(
+            self.checksum,
+            flags,
+            key_flags,
+            seq,
+            timestamp,
+            expires_at,
+            &C::serialize(&key)?,
+            &serialized_value,
+        );
+        let slot = self.append_record(&record)?;
+        self.staged.insert(
+            key,
+            Some(Entry {
+                file_id: self.file_id,
+                value_len: serialized_value.len(),
+                flags,
+                key_flags,
+                seq,
+                timestamp,
+                expires_at,
+                slot,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Fsyncs the active file, which holds every staged write, and then
+    /// applies the staged writes to the index.
+    fn promote_staged(&mut self) -> Result<()> {
+        if self.staged.is_empty() {
+            return Ok(());
+        }
+        self.flush_active_writer()?;
+        self.curr_file()?.sync_all()?;
+        for (key, entry) in std::mem::take(&mut self.staged) {
+            self.unlink(&key)?;
+            if let Some(entry) = entry {
+                self.restorable.remove(&key);
+                self.link(key, entry);
+            }
+        }
+        self.spill_cold_entries()
+    }
+
+    /// Writes the buffered overwrites to disk.
+    fn flush_writes(&mut self) -> Result<()> {
+        for (key, (serialized_value, key_flags, timestamp)) in
+            std::mem::take(&mut self.write_buffer)
+        {
+            self.write_serialized(key, serialized_value, key_flags, timestamp, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a record for `key` holding the already serialized value.
+    fn write_serialized(
+        &mut self,
+        key: K,
+        serialized_value: Vec<u8>,
+        key_flags: u32,
+        timestamp: u64,
+        expires_at: u64,
+    ) -> Result<()> {
+        // the write may land inside the buffered range
+        *self
+            .read_ahead_buf
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        let (flags, serialized_value) = self
+            .value_format()
+            .store(serialized_value, self.compressor.is_some())?;
+        if self.strict_durability {
+            return self.stage_write(
+                key,
+                serialized_value,
+                key_flags,
+                timestamp,
+                expires_at,
+                flags,
+            );
+        }
+        let overwritten = self.key_dir.get(&key).map(|entry| entry.slot.clone());
+        let hot_key = if overwritten.is_some() {
+            self.unlink(&key)?;
+            self.record_overwrite(&key)
+        } else {
+            false
+        };
+        self.restorable.remove(&key);
+        let serialized_key = C::serialize(&key)?;
+        self.evict_for(
+            &key,
+            HEADER_LEN + (serialized_key.len() + serialized_value.len()) as u64,
+        )?;
+        self.touch(&key);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let record = encode_record(
+            self.checksum,
+            flags,
+            key_flags,
+            seq,
+            timestamp,
+            expires_at,
+            &serialized_key,
+            &serialized_value,
+        );
+        let total_len = record.len() as u64;
+
+        // a larger slot is only taken if a filler record fits in what is left,
+        // and never one holding the record being replaced, which has to
+        // survive a crash partway through writing this one
+        let reusable = self.free_slots.find(total_len, |slot| {
+            let length = slot.end - slot.start;
+            (length == total_len || length >= total_len + HEADER_LEN)
+                && overwritten.as_ref().is_none_or(|old| {
+                    slot.file_id != old.file_id || slot.end <= old.start || old.end <= slot.start
+                })
+        });
+
+        if let Some(free_slot) = reusable {
+            self.free_slots.remove(&free_slot);
+            self.unsynced_files.insert(free_slot.file_id);
+            if free_slot.file_id == self.file_id {
+                // the slot may still sit in the active writer's buffer
+                self.flush_active_writer()?;
+            }
+            self.remove_hint(free_slot.file_id)?;
+            let length = free_slot.end - free_slot.start;
+            let file = self.get_file_by_id(free_slot.file_id)?;
+            let mut writer = BufWriter::new(file);
+            writer.seek(SeekFrom::Start(free_slot.start))?;
+
+            writer.write_all(&record)?;
+            let end_pos = writer.stream_position()?;
+            if length > total_len {
+                // the filler keeps the file walkable until the rest of the
+                // slot is handed out again
+                writer.write_all(&self.encode_filler(length - total_len))?;
+            }
+            writer.flush()?;
+            if length > total_len {
+                self.free_slots.insert(Slot {
+                    file_id: free_slot.file_id,
+                    start: end_pos,
+                    end: free_slot.end,
+                });
+            }
+
+            let free_slot = Slot {
+                file_id: free_slot.file_id,
+                start: free_slot.start,
+                end: end_pos,
+            };
+            self.link(
+                key,
+                Entry {
+                    file_id: free_slot.file_id,
+                    value_len: serialized_value.len(),
+                    flags,
+                    key_flags,
+                    seq,
+                    timestamp,
+                    expires_at,
+                    slot: free_slot.clone(),
+                },
+            );
+            self.is_dirty = true;
+        } else {
+            let free_slot = self.append_record(&record)?;
+            self.link(
+                key,
+                Entry {
+                    file_id: self.file_id,
+                    value_len: serialized_value.len(),
+                    flags,
+                    key_flags,
+                    seq,
+                    timestamp,
+                    expires_at,
+                    slot: free_slot,
+                },
+            );
+        }
+
+        if hot_key {
+            self.hot_files.insert(self.file_id);
+        }
+        self.spill_cold_entries()
+    }
+
+    /// Writes `record` at the end of the active file.
+    fn append_record(&mut self, record: &[u8]) -> Result<Slot> {
+        // a record larger than the limit still gets a file of its own
+        if let Some(max_file_size) = self.max_file_size {
+            if self.file_position > 0 && self.file_position + record.len() as u64 > max_file_size {
+                if !self.staged.is_empty() {
+                    self.flush_active_writer()?;
+                    self.curr_file()?.sync_all()?;
+                }
+                self.rotate()?;
+            }
+        }
+        // kept open across appends, and dropped whenever the active file
+        // changes
+        let writer = match &mut self.active_writer {
+            Some(writer) => writer,
+            None => {
+                let mut writer = BufWriter::new(self.curr_file()?);
+                writer.seek(SeekFrom::Start(self.file_position))?;
+                self.active_writer.insert(writer)
+            }
+        };
+        if self.record_separators {
+            writer.write_all(&RECORD_SEPARATOR)?;
+            self.file_position += RECORD_SEPARATOR.len() as u64;
+        }
+        writer.write_all(record)?;
+        if !self.defer_flush {
+            writer.flush()?;
+        }
+
+        let slot = Slot {
+            file_id: self.file_id,
+            start: self.file_position,
+            end: self.file_position + record.len() as u64,
+        };
+        self.file_position = slot.end;
+        self.is_dirty = true;
+        self.unsynced_files.insert(self.file_id);
+        Ok(slot)
+    }
+
+    /// Points `key` at `entry` in the index.
+    fn link(&mut self, key: K, entry: Entry) {
+        self.live_bytes += entry.slot.end - entry.slot.start;
+        if let Some(old) = self.key_dir.insert(key, entry) {
+            self.live_bytes -= old.slot.end - old.slot.start;
+        }
+    }
+
+    /// Drops `key` from the index and frees its record, without recording the
+    /// delete on disk. Returns whether the key was there.
+    fn unlink(&mut self, key: &K) -> Result<bool> {
+        self.unspill(key)?;
+        self.value_cache.remove(key);
+        self.write_buffer.remove(key);
+        self.access_times
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(key);
+        let Some(entry) = self.key_dir.remove(key) else {
+            return Ok(false);
+        };
+        self.live_bytes -= entry.slot.end - entry.slot.start;
+        let free_slot = &entry.slot;
+        // a deduplicated record stays put until its last key is gone
+        if let Some(refs) = self.slot_refs.get_mut(free_slot) {
+            *refs -= 1;
+            if *refs == 1 {
+                self.slot_refs.remove(free_slot);
+            }
+        } else if self
+            .compacting_through
+            .is_none_or(|last_file_id| free_slot.file_id > last_file_id)
+        {
+            self.free_slot(free_slot.clone());
+        }
+        self.delete_map.insert(key.clone(), entry);
+        Ok(true)
+    }
+
+    /// A record that only takes up `len` bytes, which scans skip over.
+    fn encode_filler(&self, len: u64) -> Vec<u8> {
+        encode_record(
+            self.checksum,
+            0,
+            0,
+            FILLER_SEQ,
+            0,
+            0,
+            &[],
+            &vec![0; (len - HEADER_LEN) as usize],
+        )
+    }
+
+    /// Appends an alias record to the temp file of `file_id`, saying that `key`,
+    /// as of `entry`, shares the value of the record written with `target_seq`
+    /// in the same file. Without it, only the hint file would know.
+    fn alias_to_file(
+        &self,
+        writers: &mut TempWriters,
+        key: &K,
+        target_seq: u64,
+        entry: &Entry,
+        file_id: u64,
+    ) -> Result<()> {
+        let record = encode_record(
+            self.checksum,
+            RECORD_ALIAS,
+            entry.key_flags,
+            entry.seq,
+            entry.timestamp,
+            entry.expires_at,
+            &C::serialize(key)?,
+            &target_seq.to_be_bytes(),
+        );
+        self.append_to_temp(writers, file_id, &record)?;
+        Ok(())
+    }
+
+    /// Appends `record` to the temp file of `file_id`, opening it on first
+    /// use, and returns the slot it landed in.
+    fn append_to_temp(
+        &self,
+        writers: &mut TempWriters,
+        file_id: u64,
+        record: &[u8],
+    ) -> Result<Slot> {
+        let (writer, position) = match writers.files.entry(file_id) {
+            btree_map::Entry::Occupied(open) => open.into_mut(),
+            btree_map::Entry::Vacant(unopened) => {
+                let mut file = self.get_tempfile_by_id(file_id)?;
+                let len = file.seek(SeekFrom::End(0))?;
+                unopened.insert((BufWriter::new(file), len))
+            }
+        };
+        if self.record_separators {
+            writer.write_all(&RECORD_SEPARATOR)?;
+            *position += RECORD_SEPARATOR.len() as u64;
+        }
+        writer.write_all(record)?;
+        let start = *position;
+        *position += record.len() as u64;
+        Ok(Slot {
+            file_id,
+            start,
+            end: *position,
+        })
+    }
+
+    /// Appends a record for `key` to the temp file of `file_id`, its value
+    /// compressed if `compressed` is set or a compressor is.
+    #[allow(clippy::too_many_arguments)]
+    fn serialize_to_file(
+        &self,
+        writers: &mut TempWriters,
+        key: &K,
+        value: V,
+        seq: u64,
+        key_flags: u32,
+        timestamp: u64,
+        expires_at: u64,
+        file_id: u64,
+        compressed: bool,
+    ) -> Result<Entry> {
+        let (flags, serialized_value) = self.value_format().store(
+            C::serialize(&value)?,
+            compressed || self.compressor.is_some(),
+        )?;
+        let record = encode_record(
+            self.checksum,
+            flags,
+            key_flags,
+            seq,
+            timestamp,
+            expires_at,
+            &C::serialize(&key)?,
+            &serialized_value,
+        );
+        let slot = self.append_to_temp(writers, file_id, &record)?;
+        Ok(Entry {
+            file_id,
+            value_len: serialized_value.len(),
+            flags,
+            key_flags,
+            seq,
+            timestamp,
+            expires_at,
+            slot,
+        })
+    }
+}
+
+impl<K, V, C> OnDisk<K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned + Clone,
+    C: Codec,
+{
+    /// Like `get`, but borrows the value when it is already cached instead of
+    /// reading and deserializing it again.
+    pub fn get_cow(&self, key: &K) -> Result<Option<Cow<'_, V>>> {
+        let key = &*self.normalize_key(key);
+        if let Some(value) = self.value_cache.get(key) {
+            return Ok(Some(Cow::Borrowed(value)));
+        }
+        if let Some((value_buf, ..)) = self.write_buffer.get(key) {
+            let value = C::deserialize(value_buf)?;
+            return Ok(Some(Cow::Owned(self.apply_on_read(value))));
+        }
+        match self.lookup(key)? {
+            Some(entry) if !entry.is_expired(now_millis()) => {
+                Ok(Some(Cow::Owned(self.load_value(&entry)?)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<K, T, C> OnDisk<K, Vec<T>, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    T: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Appends `value` to the list stored under `key`, starting a new list if
+    /// there is none. The whole list is read and written back.
+    pub fn append_to_list(&mut self, key: K, value: T) -> Result<()> {
+        let mut list = self.get(&key)?.unwrap_or_default();
+        list.push(value);
+        self.write_value(key, list, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Keys that carry the namespace (tenant, table, ...) they belong to.
+pub trait Namespaced {
+    type Namespace: Ord + Clone;
+
+    /// The namespace of this key, or `None` for keys outside any namespace.
+    fn namespace(&self) -> Option<Self::Namespace>;
+}
+
+/// The namespace type of keys `K`.
+pub type NamespaceId<K> = <K as Namespaced>::Namespace;
+
+/// String keys are namespaced by whatever precedes the first `:`, as in
+/// `"users:42"`.
+impl Namespaced for String {
+    type Namespace = String;
+
+    fn namespace(&self) -> Option<String> {
+        self.split_once(':')
+            .map(|(namespace, _)| namespace.to_string())
+    }
+}
+
+impl<K, V, C> OnDisk<K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone + Namespaced,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Returns the distinct namespaces of the live keys, in order.
+    pub fn namespaces(&self) -> Result<Vec<NamespaceId<K>>> {
+        let spilled = self.spilled_entries()?;
+        let namespaces: BTreeSet<_> = self
+            .key_dir
+            .keys()
+            .chain(spilled.iter().map(|(key, _)| key))
+            .filter_map(Namespaced::namespace)
+            .collect();
+        Ok(namespaces.into_iter().collect())
+    }
+}
+
+impl<K, V, C> Drop for OnDisk<K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// A best effort `close`: errors are lost, so call `close` where the last
+    /// writes matter.
+    fn drop(&mut self) {
+        if self.closed || self.read_only {
+            return;
+        }
+        if self.sync().is_ok() {
+            let _ = fs::write(format!("{}.clean", self.prefix), []);
+        }
+    }
+}
+
+/// Iterates as `iter` does, live entries in key order with each value read as
+/// the iterator reaches it, but yields bare pairs. The first error ends the
+/// iteration and is kept for `take_iter_error`.
+impl<'a, K, V, C> IntoIterator for &'a OnDisk<K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    type Item = (K, V);
+    type IntoIter = Box<dyn Iterator<Item = (K, V)> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.take_iter_error();
+        Box::new(self.iter().map_while(move |item| match item {
+            Ok(item) => Some(item),
+            Err(e) => {
+                *self
+                    .iter_error
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(e);
+                None
+            }
+        }))
+    }
+}
+
+impl<K, V, C> Db<K, V> for OnDisk<K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    fn get(&self, key: &K) -> Result<Option<V>> {
+        let key = &*self.normalize_key(key);
+        self.touch(key);
+        if let Some((value_buf, ..)) = self.write_buffer.get(key) {
+            return Ok(Some(self.apply_on_read(C::deserialize(value_buf)?)));
+        }
+        match self.lookup(key)? {
+            Some(entry) if !entry.is_expired(now_millis()) => Ok(Some(self.load_value(&entry)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn put(&mut self, key: K, value: V) -> Result<Option<V>> {
+        let previous = self.previous_value(&key)?;
+        self.write_value(key, value, 0, 0)?;
+        Ok(previous)
+    }
+
+    fn delete(&mut self, key: &K) -> Result<()> {
+        self.check_writable()?;
+        let key = &*self.normalize_key(key);
+        let deleted = if self.strict_durability {
+            self.write_buffer.remove(key);
+            let live = match self.staged.get(key) {
+                Some(entry) => entry.is_some(),
+                None => self.lookup(key)?.is_some(),
+            };
+            if live {
+                self.staged.insert(key.clone(), None);
+            }
+            live
+        } else {
+            self.unlink(key)?
+        };
+        if deleted {
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            let tombstone = encode_record(
+                self.checksum,
+                RECORD_TOMBSTONE,
+                0,
+                seq,
+                now_millis(),
+                0,
+                &C::serialize(key)?,
+                &[],
+            );
+            self.append_record(&tombstone)?;
+            self.maybe_auto_compact()?;
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if self.compacting_through.is_some() {
+            bail!("cannot clear while a compaction is in progress");
+        }
+        self.check_no_snapshots("clear")?;
+        self.release_files();
+        for file_id in data_file_ids(&self.prefix)? {
+            self.remove_hint(file_id)?;
+            fs::remove_file(format!("{}.{}.db", self.prefix, file_id))?;
+        }
+        self.discard_spill()?;
+        File::create(format!("{}.1.db", self.prefix))?;
+        File::open(data_dir(&self.prefix))?.sync_all()?;
+
+        self.key_dir = BTreeMap::new();
+        self.live_bytes = 0;
+        self.delete_map = BTreeMap::new();
+        self.restorable = BTreeSet::new();
+        self.free_slots = FreeSlots::default();
+        self.deferred_slots = Vec::new();
+        self.slot_refs = BTreeMap::new();
+        self.overwrite_counts = BTreeMap::new();
+        self.hot_files = BTreeSet::new();
+        self.value_cache = BTreeMap::new();
+        self.write_buffer = BTreeMap::new();
+        self.staged = BTreeMap::new();
+        self.unsynced_files = BTreeSet::new();
+        self.access_times
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        self.next_seq = 0;
+        self.synced_seq = 0;
+        self.file_id = 1;
+        self.file_position = 0;
+        self.is_dirty = false;
+        Ok(())
+    }
+
+    fn keys(&mut self) -> Result<Vec<&K>> {
+        self.load_spilled()?;
+        let keys: Vec<_> = self.key_dir.keys().collect();
+        Ok(keys)
+    }
+
+    fn values(&mut self) -> Result<Vec<V>> {
+        self.load_spilled()?;
+        let mut values = vec![];
+        for value in self.key_dir.keys() {
+            if let Some(v) = self.get(value)? {
+                values.push(v);
+            }
+        }
+        Ok(values)
+    }
+
+    fn items(&mut self) -> Result<Vec<(&K, V)>> {
+        self.load_spilled()?;
+        let mut items = vec![];
+        for k in self.key_dir.keys() {
+            let v = self.get(k)?.ok_or_else(|| anyhow!("could not find key"))?;
+            items.push((k, v));
+        }
+        Ok(items)
+    }
+
+    fn len(&self) -> usize {
+        // every shadowed key is also in the spill file
+        self.key_dir.len() + self.spill_len - self.spill_shadowed.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains_key(&self, key: &K) -> Result<bool> {
+        // only spilled keys need a read, of the spill file
+        Ok(self
+            .lookup(&self.normalize_key(key))?
+            .is_some_and(|entry| !entry.is_expired(now_millis())))
+    }
+}
+
+impl<K, V, C> ToDisk<K, V> for OnDisk<K, V, C>
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    fn open(file_name: &str) -> Result<Self> {
+        settle_interrupted_prune(file_name)?;
+        let db_name = format!("{}.{}.db", file_name, 1);
+        let _ = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(db_name)?;
+        if !fs::exists(format!("{}.schema", file_name))? {
+            Schema::of::<K, V, C>().write(file_name)?;
+        }
+        // a crash from here on leaves the marker missing
+        let clean_marker = format!("{}.clean", file_name);
+        if fs::exists(&clean_marker)? {
+            fs::remove_file(clean_marker)?;
+        }
+        let mut db = Self::unopened(file_name, false);
+        db.recover()?;
+        Ok(db)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.compact_hot_files()?;
+        self.flush()?;
+        if self.is_dirty {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.flush_writes()?;
+        self.flush_active_writer()?;
+        self.promote_staged()?;
+        // writes into reused space can touch older files too
+        while let Some(&file_id) = self.unsynced_files.first() {
+            match File::open(format!("{}.{}.db", self.prefix, file_id)) {
+                Ok(file) => file.sync_all()?,
+                // removed since, with its live records copied elsewhere
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.unsynced_files.remove(&file_id);
+        }
+        self.synced_seq = self.next_seq;
+        // and rotating created the active file since the last sync
+        File::open(data_dir(&self.prefix))?.sync_all()?;
+        Ok(())
+    }
+
+    fn prune(&mut self) -> Result<()> {
+        self.prune_with_progress(|_, _| {})
+    }
+
+    fn prune_with_progress<F: FnMut(usize, usize)>(&mut self, mut cb: F) -> Result<()> {
+        self.check_writable()?;
+        if self.compacting_through.is_some() {
+            bail!("cannot prune while a compaction is in progress");
+        }
+        self.check_no_snapshots("prune")?;
+        self.flush_writes()?;
+        self.promote_staged()?;
+        self.restorable = BTreeSet::new();
+        self.load_spilled()?;
+        let now = now_millis();
+        let expired: Vec<K> = self
+            .key_dir
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.unlink(&key)?;
+            self.is_dirty = true;
+        }
+        // besides fresh writes, anything on disk no key points at, like
+        // overwritten records and tombstones from before a sync or a reopen,
+        // is worth a rewrite
+        let live_bytes: u64 = self
+            .key_dir
+            .values()
+            .map(|entry| &entry.slot)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|slot| slot.end - slot.start)
+            .sum();
+        let mut disk_bytes = 0;
+        for file_id in data_file_ids(&self.prefix)? {
+            disk_bytes += fs::metadata(format!("{}.{}.db", self.prefix, file_id))?.len();
+        }
+        if self.is_dirty || disk_bytes > live_bytes {
+            let total = self.key_dir.len();
+            let mut processed = 0;
+            let mut files_to_swap = BTreeSet::new();
+            let mut new_key_dir = BTreeMap::new();
+            let mut slot_refs = BTreeMap::new();
+            let mut written_values: BTreeMap<u32, Vec<(Vec<u8>, Entry)>> = BTreeMap::new();
+            let mut writers = TempWriters::default();
+            if self.compaction_threads > 1 && !self.dedup_on_prune {
+                let mut keys_by_file: BTreeMap<u64, Vec<&K>> = BTreeMap::new();
+                let mut records_by_file: BTreeMap<u64, Vec<_>> = BTreeMap::new();
+                for (key, entry) in &self.key_dir {
+                    keys_by_file.entry(entry.file_id).or_default().push(key);
+                    records_by_file
+                        .entry(entry.file_id)
+                        .or_default()
+                        .push((C::serialize(key)?, entry.clone()));
+                }
+
+                let worker_count = self.compaction_threads.min(records_by_file.len());
+                let mut workers = vec![vec![]; worker_count];
+                for (i, file) in records_by_file.into_iter().enumerate() {
+                    workers[i % worker_count].push(file);
+                }
+
+                let checksum = self.checksum;
+                let prefix = self.prefix.as_str();
+                let compress_on_prune = self.compress_on_prune;
+                let compress_all = self.compressor.is_some();
+                let format = self.value_format();
+                let separators = self.record_separators;
+                let active_file_id = self.file_id;
+                let copied = std::thread::scope(|scope| {
+                    let handles: Vec<_> = workers
+                        .into_iter()
+                        .map(|files| {
+                            scope.spawn(move || {
+                                files
+                                    .into_iter()
+                                    .map(|(file_id, records)| {
+                                        let compressed = compress_all
+                                            || (compress_on_prune && file_id != active_file_id);
+                                        let entries = copy_records(
+                                            checksum, prefix, file_id, records, compressed, format,
+                                            separators,
+                                        )?;
+                                        Ok((file_id, entries))
+                                    })
+                                    .collect::<Result<Vec<_>>>()
+                            })
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .map(|handle| {
+                            handle
+                                .join()
+                                .unwrap_or_else(|_| Err(anyhow!("compaction thread panicked")))
+                        })
+                        .collect::<Vec<_>>()
+                });
+
+                for files in copied {
+                    for (file_id, entries) in files? {
+                        let keys = &keys_by_file[&file_id];
+                        processed += entries.len();
+                        for (key, entry) in keys.iter().zip(entries) {
+                            new_key_dir.insert((*key).clone(), entry);
+                        }
+                        files_to_swap.insert(file_id);
+                        cb(processed, total);
+                    }
+                }
+            }
+            for (key, entry) in self
+                .key_dir
+                .iter()
+                .filter(|_| self.compaction_threads == 1 || self.dedup_on_prune)
+            {
+                let value_buf = self.read_value_bytes(entry)?;
+
+                let checksum = self.checksum.crc().checksum(&value_buf);
+                let written = written_values
+                    .get(&checksum)
+                    .filter(|_| self.dedup_on_prune)
+                    .and_then(|candidates| {
+                        candidates.iter().find(|(bytes, shared)| {
+                            *bytes == value_buf
+                                && shared.key_flags == entry.key_flags
+                                && shared.expires_at == entry.expires_at
+                        })
+                    });
+
+                if let Some((_, shared)) = written {
+                    self.alias_to_file(&mut writers, key, shared.seq, entry, shared.file_id)?;
+                    *slot_refs.entry(shared.slot.clone()).or_insert(1) += 1;
+                    // the shared record keeps the first key's sequence, so
+                    // carry this key's own write order over
+                    let mut shared = shared.clone();
+                    shared.seq = entry.seq;
+                    shared.timestamp = entry.timestamp;
+                    new_key_dir.insert(key.clone(), shared);
+                } else {
+                    let value: V = C::deserialize(&value_buf)?;
+
+                    // then write it to tempfile, compressed unless it stays
+                    // in the active file
+                    let compressed = self.compress_on_prune && entry.file_id != self.file_id;
+                    let new_entry = self.serialize_to_file(
+                        &mut writers,
+                        key,
+                        value,
+                        entry.seq,
+                        entry.key_flags,
+                        entry.timestamp,
+                        entry.expires_at,
+                        entry.file_id,
+                        compressed,
+                    )?;
+                    new_key_dir.insert(key.clone(), new_entry.clone());
+
+                    // Finally, swap tempfile and file
+                    files_to_swap.insert(new_entry.file_id);
+                    if self.dedup_on_prune {
+                        written_values
+                            .entry(checksum)
+                            .or_default()
+                            .push((value_buf, new_entry));
+                    }
+                }
+
+                processed += 1;
+                cb(processed, total);
+            }
+            writers.flush()?;
+            drop(writers);
+
+            // the compacted files are joined into as few files as the size
+            // limit allows, numbered from 1; files with nothing live left,
+            // only dead records and tombstones, are dropped
+            let prefix = self.prefix.clone();
+            let compacted_path = |f_id| format!("{}.{}.compacted.db", prefix, f_id);
+            let mut moved_to = BTreeMap::new();
+            let mut new_file_id = 1;
+            let mut writer = BufWriter::new(File::create(compacted_path(new_file_id))?);
+            let mut position = 0;
+            for &file_id in &files_to_swap {
+                let temp_file_path = format!("{}.{}.temp.db", self.prefix, file_id);
+                if !fs::exists(&temp_file_path)? {
+                    continue;
+                }
+                let len = fs::metadata(&temp_file_path)?.len();
+                if let Some(max_file_size) = self.max_file_size {
+                    if position > 0 && position + len > max_file_size {
+                        writer.flush()?;
+                        writer.get_ref().sync_all()?;
+                        new_file_id += 1;
+                        writer = BufWriter::new(File::create(compacted_path(new_file_id))?);
+                        position = 0;
+                    }
+                }
+                std::io::copy(&mut File::open(&temp_file_path)?, &mut writer)?;
+                fs::remove_file(temp_file_path)?;
+                moved_to.insert(file_id, (new_file_id, position));
+                position += len;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            drop(writer);
+
+            // once the marker is durable, open finishes the swap if a crash
+            // interrupts it, and before that it throws the compacted files away
+            File::open(data_dir(&self.prefix))?.sync_all()?;
+            let marker = File::create(prune_marker_path(&self.prefix))?;
+            (&marker).write_all(new_file_id.to_string().as_bytes())?;
+            marker.sync_all()?;
+            File::open(data_dir(&self.prefix))?.sync_all()?;
+            self.release_files();
+            self.hinted_files = BTreeSet::new();
+            swap_in_compacted_files(&self.prefix, new_file_id)?;
+
+            let relocate = |slot: &mut Slot| {
+                let (new_file_id, offset) = moved_to[&slot.file_id];
+                slot.file_id = new_file_id;
+                slot.start += offset;
+                slot.end += offset;
+            };
+            for entry in new_key_dir.values_mut() {
+                relocate(&mut entry.slot);
+                entry.file_id = entry.slot.file_id;
+            }
+            let mut hints: BTreeMap<u64, Vec<_>> = BTreeMap::new();
+            for (key, entry) in &new_key_dir {
+                hints.entry(entry.file_id).or_default().push((key, entry));
+            }
+            for (file_id, entries) in hints {
+                self.write_hint(file_id, &entries)?;
+            }
+            File::open(data_dir(&self.prefix))?.sync_all()?;
+            let slot_refs = slot_refs
+                .into_iter()
+                .map(|(mut slot, refs)| {
+                    relocate(&mut slot);
+                    (slot, refs)
+                })
+                .collect();
+            self.unsynced_files = BTreeSet::new();
+            self.synced_seq = self.next_seq;
+            self.file_id = new_file_id;
+
+            self.delete_map = BTreeMap::new();
+            self.free_slots = FreeSlots::default();
+            self.deferred_slots = Vec::new();
+            self.slot_refs = slot_refs;
+            self.overwrite_counts = BTreeMap::new();
+            self.hot_files = BTreeSet::new();
+            self.live_bytes = new_key_dir
+                .values()
+                .map(|entry| entry.slot.end - entry.slot.start)
+                .sum();
+            self.key_dir = new_key_dir;
+            // new writes go after the compacted files rather than into them
+            self.rotate()?;
+        }
+        self.spill_cold_entries()?;
+
+        Ok(())
+    }
+
+    fn compact_deletes(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if self.compacting_through.is_some() {
+            bail!("cannot compact while a compaction is in progress");
+        }
+        self.check_no_snapshots("compact")?;
+        self.flush_writes()?;
+        self.promote_staged()?;
+        self.load_spilled()?;
+        // only files holding deleted or overwritten records get rewritten
+        let mut file_ids: BTreeSet<u64> = self.free_slots.iter().map(|slot| slot.file_id).collect();
+        file_ids.extend(self.delete_map.values().map(|entry| entry.file_id));
+
+        for file_id in file_ids {
+            self.rewrite_file(file_id)?;
+        }
+        self.spill_cold_entries()
+    }
+
+    fn replace_all<I: IntoIterator<Item = (K, V)>>(&mut self, items: I) -> Result<()> {
+        self.check_writable()?;
+        if self.compacting_through.is_some() {
+            bail!("cannot replace the dataset while a compaction is in progress");
+        }
+        self.check_no_snapshots("replace the dataset")?;
+        // the new dataset is written beside the data files and swapped in
+        // the way `prune` swaps in compacted files, so nothing old is touched
+        // until it is fully written and durable
+        let new_file_id = 1;
+        let temp_file_path = format!("{}.{}.temp.db", self.prefix, new_file_id);
+        let compacted_path = format!("{}.{}.compacted.db", self.prefix, new_file_id);
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_file_path)?;
+
+        let mut new_key_dir = BTreeMap::new();
+        let mut next_seq = self.next_seq;
+        let mut writers = TempWriters::default();
+        let written = items.into_iter().try_for_each(|(key, value)| {
+            let entry = self.serialize_to_file(
+                &mut writers,
+                &key,
+                value,
+                next_seq,
+                0,
+                now_millis(),
+                0,
+                new_file_id,
+                false,
+            )?;
+            next_seq += 1;
+            new_key_dir.insert(key, entry);
+            Ok(())
+        });
+        let written = written.and_then(|()| writers.flush());
+        drop(writers);
+        if let Err(e) = written {
+            fs::remove_file(&temp_file_path)?;
+            return Err(e);
+        }
+
+        let tempfile = self.get_tempfile_by_id(new_file_id)?;
+        tempfile.sync_all()?;
+        let file_position = tempfile.metadata()?.len();
+        fs::rename(temp_file_path, compacted_path)?;
+
+        File::open(data_dir(&self.prefix))?.sync_all()?;
+        let marker = File::create(prune_marker_path(&self.prefix))?;
+        (&marker).write_all(new_file_id.to_string().as_bytes())?;
+        marker.sync_all()?;
+        File::open(data_dir(&self.prefix))?.sync_all()?;
+        self.release_files();
+        self.hinted_files = BTreeSet::new();
+        self.unsynced_files = BTreeSet::new();
+        swap_in_compacted_files(&self.prefix, new_file_id)?;
+
+        self.discard_spill()?;
+        self.live_bytes = new_key_dir
+            .values()
+            .map(|entry| entry.slot.end - entry.slot.start)
+            .sum();
+        self.key_dir = new_key_dir;
+        self.delete_map = BTreeMap::new();
+        self.restorable = BTreeSet::new();
+        self.free_slots = FreeSlots::default();
+        self.deferred_slots = Vec::new();
+        self.slot_refs = BTreeMap::new();
+        self.value_cache = BTreeMap::new();
+        self.write_buffer = BTreeMap::new();
+        self.staged = BTreeMap::new();
+        self.next_seq = next_seq;
+        self.synced_seq = next_seq;
+        self.file_id = new_file_id;
+        self.file_position = file_position;
+        self.is_dirty = true;
+
+        Ok(())
+    }
+}
+
+use Op::*;
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Op<K, V> {
+    Put { key: K, value: V },
+    Delete { key: K },
+    Update { key: K, value: V },
+    Prune,
+    Sync,
+}
+
+pub fn eval_op<K, V, C>(db: &mut OnDisk<K, V, C>, op: Op<K, V>)
+where
+    K: PartialOrd + Ord + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Clone,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    match op {
+        Put { key, value } => {
+            db.put(key, value).unwrap();
+        }
+        Delete { key } => {
+            db.delete(&key).unwrap();
+        }
+        Update { key, value } => {
+            db.put(key, value).unwrap();
+        }
+        Prune => {
+            db.prune().unwrap();
+        }
+        Sync => db.sync().unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::ser::Error;
+    use serde::{Deserialize, Serializer};
+    use std::io::Cursor;
+
+    /// A value that refuses to serialize when it holds `u64::MAX`.
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Fallible(u64);
+
+    impl Serialize for Fallible {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            if self.0 == u64::MAX {
+                return Err(S::Error::custom("refusing to serialize"));
+            }
+            serializer.serialize_newtype_struct("Fallible", &self.0)
+        }
+    }
+
+    /// Removes the files a previous run left for the database at `prefix`.
+    fn remove_db(prefix: &str) {
+        let name = Path::new(prefix).file_name().unwrap().to_str().unwrap();
+        let Ok(files) = fs::read_dir(data_dir(prefix)) else {
+            return;
+        };
+        for file in files {
+            let path = file.unwrap().path();
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            if !file_name.starts_with(&format!("{}.", name)) {
+                continue;
+            }
+            if path.is_dir() {
+                fs::remove_dir_all(path).unwrap();
+            } else {
+                fs::remove_file(path).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn crash_1() {
+        remove_db("crash_1");
+        let mut db: OnDisk<String, u64> = OnDisk::open("crash_1").unwrap();
+
+        let instructions = vec![
+            Delete {
+                key: "".to_string(),
+            },
+            Delete {
+                key: "".to_string(),
+            },
+            Put {
+                key: "\0".to_string(),
+                value: 10344644575756526533u64,
+            },
+            Put {
+                key: "\u{1}".to_string(),
+                value: 72339073326448897u64,
+            },
+            Put {
+                key: "\u{1}".to_string(),
+                value: 72057594037928193u64,
+            },
+            Put {
+                key: "".to_string(),
+                value: 0,
+            },
+        ];
+
+        for instruction in instructions {
+            eval_op(&mut db, instruction);
+        }
+
+        assert!(db.sync().is_ok());
+    }
+
+    #[test]
+    fn crash_2() {
+        remove_db("crash_2");
+        let mut db: OnDisk<String, u64> = OnDisk::open("crash_2").unwrap();
+
+        let instructions = vec![
+            Put {
+                key: "\0".to_string(),
+                value: 16969173279757565696,
+            },
+            Sync,
+            Prune,
+        ];
+
+        for instruction in instructions {
+            eval_op(&mut db, instruction);
+        }
+
+        assert!(db.sync().is_ok());
+    }
+
+    #[test]
+    fn prune_reports_progress() {
+        remove_db("prune_progress");
+        let mut db: OnDisk<String, u64> = OnDisk::open("prune_progress").unwrap();
+
+        for i in 0..10 {
+            db.put(i.to_string(), i).unwrap();
+        }
+
+        let mut calls = vec![];
+        db.prune_with_progress(|processed, total| calls.push((processed, total)))
+            .unwrap();
+
+        assert_eq!(calls.len(), 10);
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(calls.iter().all(|&(_, total)| total == 10));
+        assert_eq!(calls.last(), Some(&(10, 10)));
+    }
+
+    #[test]
+    fn value_pos_is_computed_from_slot() {
+        remove_db("value_pos");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("value_pos").unwrap();
+
+        for i in 0..5 {
+            db.put(i, i * 100).unwrap();
+        }
+
+        let mut file = db.curr_file().unwrap();
+        for i in 0..5 {
+            let Entry {
+                value_len, slot, ..
+            } = db.key_dir.get(&i).unwrap();
+            let key_len = bincode::serialized_size(&i).unwrap();
+            assert_eq!(
+                compute_value_pos(*value_len, slot),
+                slot.start + HEADER_LEN + key_len
+            );
+
+            let mut value_buf = vec![0u8; *value_len];
+            file.seek(SeekFrom::Start(compute_value_pos(*value_len, slot)))
+                .unwrap();
+            file.read_exact(&mut value_buf).unwrap();
+            assert_eq!(bincode::deserialize::<u64>(&value_buf).unwrap(), i * 100);
+            assert_eq!(db.get(&i).unwrap(), Some(i * 100));
+        }
+    }
+
+    #[test]
+    fn replace_all_swaps_dataset() {
+        remove_db("replace_all");
+        let mut db: OnDisk<String, Fallible> = OnDisk::open("replace_all").unwrap();
+        db.put("a".to_string(), Fallible(1)).unwrap();
+        db.put("b".to_string(), Fallible(2)).unwrap();
+
+        let failed = db.replace_all(vec![
+            ("c".to_string(), Fallible(3)),
+            ("d".to_string(), Fallible(u64::MAX)),
+        ]);
+        assert!(failed.is_err());
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(Fallible(1)));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some(Fallible(2)));
+        assert_eq!(db.get(&"c".to_string()).unwrap(), None);
+
+        db.replace_all(vec![("c".to_string(), Fallible(3))])
+            .unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"b".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"c".to_string()).unwrap(), Some(Fallible(3)));
+
+        db.put("e".to_string(), Fallible(5)).unwrap();
+        assert_eq!(db.get(&"c".to_string()).unwrap(), Some(Fallible(3)));
+        assert_eq!(db.get(&"e".to_string()).unwrap(), Some(Fallible(5)));
+    }
+
+    #[test]
+    fn value_len_matches_written_bytes() {
+        remove_db("value_len");
+        let mut db: OnDisk<String, String> = OnDisk::open("value_len").unwrap();
+        let value = "x".repeat(100);
+        db.put("a".to_string(), value.clone()).unwrap();
+
+        assert_eq!(
+            db.value_len(&"a".to_string()).unwrap(),
+            Some(bincode::serialized_size(&value).unwrap() as usize)
+        );
+        assert_eq!(db.value_len(&"b".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn dedup_prune_shares_identical_values() {
+        remove_db("dedup_prune");
+        let mut db: OnDisk<String, String> = OnDisk::open("dedup_prune").unwrap();
+        db.set_dedup_on_prune(true);
+        db.put("a".to_string(), "same".to_string()).unwrap();
+        db.put("b".to_string(), "same".to_string()).unwrap();
+        db.put("c".to_string(), "different".to_string()).unwrap();
+        db.prune().unwrap();
+
+        let a_slot = db.key_dir.get("a").unwrap().slot.clone();
+        let b_slot = db.key_dir.get("b").unwrap().slot.clone();
+        let c_slot = db.key_dir.get("c").unwrap().slot.clone();
+        assert_eq!(a_slot, b_slot);
+        assert_ne!(a_slot, c_slot);
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some("same".to_string()));
+
+        // freeing one of the keys must not hand the shared record out for reuse
+        db.delete(&"a".to_string()).unwrap();
+        db.put("d".to_string(), "same".to_string()).unwrap();
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some("same".to_string()));
+        assert_eq!(db.get(&"d".to_string()).unwrap(), Some("same".to_string()));
+    }
+
+    #[test]
+    fn unsynced_keys_since_last_sync() {
+        remove_db("unsynced_keys");
+        let mut db: OnDisk<String, u64> = OnDisk::open("unsynced_keys").unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        db.put("b".to_string(), 2).unwrap();
+        assert_eq!(db.unsynced_keys().unwrap(), vec!["a", "b"]);
+
+        db.sync().unwrap();
+        assert!(db.unsynced_keys().unwrap().is_empty());
+
+        db.put("c".to_string(), 3).unwrap();
+        assert_eq!(db.unsynced_keys().unwrap(), vec!["c"]);
+    }
+
+    #[test]
+    fn unsynced_keys_outside_the_active_file() {
+        remove_db("unsynced_elsewhere");
+        let mut db: OnDisk<String, u64> = OnDisk::open("unsynced_elsewhere").unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        db.put("b".to_string(), 2).unwrap();
+        db.sync().unwrap();
+
+        // "c" takes the space "a" left in the synced file
+        db.delete(&"a".to_string()).unwrap();
+        db.put("c".to_string(), 3).unwrap();
+        assert_eq!(db.byte_range(&"c".to_string()).unwrap().unwrap().0, 1);
+        assert_eq!(db.unsynced_keys().unwrap(), vec!["c"]);
+        drop(db);
+        let mut db: OnDisk<String, u64> = OnDisk::open("unsynced_elsewhere").unwrap();
+        assert!(db.unsynced_keys().unwrap().is_empty());
+        db.put("e".to_string(), 5).unwrap();
+        assert_eq!(db.unsynced_keys().unwrap(), vec!["e"]);
+        db.sync().unwrap();
+
+        db.set_write_coalescing(Some(16)).unwrap();
+        db.put("b".to_string(), 20).unwrap();
+        assert_eq!(db.unsynced_keys().unwrap(), vec!["b"]);
+        db.sync().unwrap();
+        assert!(db.unsynced_keys().unwrap().is_empty());
+
+        db.set_strict_durability(true).unwrap();
+        db.put("d".to_string(), 4).unwrap();
+        assert_eq!(db.unsynced_keys().unwrap(), vec!["d"]);
+        db.sync().unwrap();
+        assert!(db.unsynced_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unsynced_keys_include_spilled_ones() {
+        remove_db("unsynced_spilled");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("unsynced_spilled").unwrap();
+        db.set_max_file_size(Some(256));
+        db.set_key_dir_limit(Some(8)).unwrap();
+        for i in 0..50 {
+            db.put(i, i).unwrap();
+        }
+        // files filled up and rotated without a sync, and their keys spilled
+        assert!(db.spill_len > 0);
+        assert_eq!(db.unsynced_keys().unwrap(), (0..50).collect::<Vec<_>>());
+        db.sync().unwrap();
+        assert!(db.unsynced_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn hot_key_overwrites_stay_bounded() {
+        remove_db("hot_key");
+        let mut db: OnDisk<String, String> = OnDisk::open("hot_key").unwrap();
+        db.set_hot_key_threshold(Some(10));
+        db.put("other".to_string(), "cold".to_string()).unwrap();
+
+        for i in 0..200 {
+            db.put("counter".to_string(), "x".repeat(i)).unwrap();
+        }
+        let record_len = HEADER_LEN
+            + bincode::serialized_size(&"counter".to_string()).unwrap()
+            + bincode::serialized_size(&"x".repeat(199)).unwrap();
+        // puts leave the rewrite to the next sync
+        let file_len = || fs::metadata("hot_key.1.db").unwrap().len();
+        assert!(file_len() > 100 * record_len);
+
+        db.sync().unwrap();
+        assert!(file_len() < 2 * record_len);
+        assert_eq!(db.file_id, 2);
+        assert_eq!(
+            db.get(&"counter".to_string()).unwrap(),
+            Some("x".repeat(199))
+        );
+        assert_eq!(
+            db.get(&"other".to_string()).unwrap(),
+            Some("cold".to_string())
+        );
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_reads_match_file_reads() {
+        remove_db("mmap_reads");
+        let mut db: OnDisk<String, String> = OnDisk::open("mmap_reads").unwrap();
+        for i in 0..20 {
+            db.put(i.to_string(), "v".repeat(i)).unwrap();
+        }
+        let expected = db
+            .items()
+            .unwrap()
+            .into_iter()
+            .map(|(_, v)| v)
+            .collect::<Vec<_>>();
+
+        db.set_mmap(true);
+        assert_eq!(db.values().unwrap(), expected);
+
+        // the mapping has to follow the file as it grows and gets rewritten
+        db.put("late".to_string(), "after mapping".to_string())
+            .unwrap();
+        assert_eq!(
+            db.get(&"late".to_string()).unwrap(),
+            Some("after mapping".to_string())
+        );
+        db.delete(&"3".to_string()).unwrap();
+        db.prune().unwrap();
+        assert_eq!(db.get(&"4".to_string()).unwrap(), Some("v".repeat(4)));
+        assert_eq!(
+            db.get(&"late".to_string()).unwrap(),
+            Some("after mapping".to_string())
+        );
+    }
+
+    #[test]
+    fn get_cow_borrows_cached_values() {
+        remove_db("get_cow");
+        let mut db: OnDisk<String, String> = OnDisk::open("get_cow").unwrap();
+        let key = "a".to_string();
+        db.put(key.clone(), "first".to_string()).unwrap();
+
+        let value = db.get_cow(&key).unwrap().unwrap();
+        assert!(matches!(value, Cow::Owned(ref v) if v == "first"));
+
+        assert!(db.cache_value(&key).unwrap());
+        let value = db.get_cow(&key).unwrap().unwrap();
+        assert!(matches!(value, Cow::Borrowed(v) if v == "first"));
+
+        // writes invalidate the cached copy
+        db.put(key.clone(), "second".to_string()).unwrap();
+        let value = db.get_cow(&key).unwrap().unwrap();
+        assert!(matches!(value, Cow::Owned(ref v) if v == "second"));
+
+        assert!(!db.cache_value(&"missing".to_string()).unwrap());
+        assert!(db.get_cow(&"missing".to_string()).unwrap().is_none());
+    }
+
+    #[test]
+    fn online_compaction_keeps_concurrent_writes() {
+        remove_db("online_compaction");
+        let mut db: OnDisk<String, u64> = OnDisk::open("online_compaction").unwrap();
+        for i in 0..10 {
+            db.put(i.to_string(), i).unwrap();
+        }
+        db.sync().unwrap();
+        db.put("1".to_string(), 100).unwrap();
+        db.delete(&"2".to_string()).unwrap();
+
+        let mut compaction = db.begin_compaction().unwrap();
+        db.copy_compaction(&mut compaction).unwrap();
+
+        // writes that land between the copy and the swap
+        db.put("3".to_string(), 300).unwrap();
+        db.delete(&"4".to_string()).unwrap();
+        db.put("new".to_string(), 1000).unwrap();
+
+        db.finish_compaction(compaction).unwrap();
+
+        assert_eq!(db.get(&"0".to_string()).unwrap(), Some(0));
+        assert_eq!(db.get(&"1".to_string()).unwrap(), Some(100));
+        assert_eq!(db.get(&"2".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"3".to_string()).unwrap(), Some(300));
+        assert_eq!(db.get(&"4".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"5".to_string()).unwrap(), Some(5));
+        assert_eq!(db.get(&"new".to_string()).unwrap(), Some(1000));
+        assert!(!fs::exists("online_compaction.1.db").unwrap());
+        assert!(fs::exists("online_compaction.2.db").unwrap());
+    }
+
+    #[test]
+    fn reads_reuse_open_files() {
+        remove_db("open_files");
+        let mut db: OnDisk<String, u64> = OnDisk::open("open_files").unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        db.sync().unwrap();
+        db.put("b".to_string(), 2).unwrap();
+
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(1));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some(2));
+        assert_eq!(db.open_files.lock().unwrap().len(), 2);
+
+        // a pooled handle keeps working even once the path is gone, which
+        // only holds if the file isn't opened again
+        fs::rename("open_files.1.db", "open_files.1.moved.db").unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(1));
+        fs::rename("open_files.1.moved.db", "open_files.1.db").unwrap();
+
+        db.set_max_open_files(1);
+        assert_eq!(db.open_files.lock().unwrap().len(), 1);
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(1));
+        assert_eq!(db.open_files.lock().unwrap()[0].0, 1);
+    }
+
+    #[test]
+    fn schema_file_describes_database() {
+        remove_db("schema");
+        let db: OnDisk<String, u64> = OnDisk::open("schema").unwrap();
+
+        let schema = Schema::load("schema").unwrap();
+        assert_eq!(schema, db.schema());
+        assert_eq!(schema.key_type, "alloc::string::String");
+        assert_eq!(schema.value_type, "u64");
+        assert_eq!(schema.codec, "bincode");
+        assert_eq!(schema.format_version, FORMAT_VERSION);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn compact_deletes_skips_clean_files() {
+        use std::os::unix::fs::MetadataExt;
+
+        remove_db("compact_deletes");
+        let mut db: OnDisk<String, String> = OnDisk::open("compact_deletes").unwrap();
+        db.put("a".to_string(), "x".repeat(100)).unwrap();
+        db.put("b".to_string(), "x".repeat(100)).unwrap();
+        db.sync().unwrap();
+        db.put("c".to_string(), "x".repeat(100)).unwrap();
+        db.put("d".to_string(), "x".repeat(100)).unwrap();
+        db.delete(&"c".to_string()).unwrap();
+
+        let clean = fs::metadata("compact_deletes.1.db").unwrap();
+        let dirty = fs::metadata("compact_deletes.2.db").unwrap();
+        db.compact_deletes().unwrap();
+
+        assert_eq!(
+            fs::metadata("compact_deletes.1.db").unwrap().ino(),
+            clean.ino()
+        );
+        assert!(fs::metadata("compact_deletes.2.db").unwrap().len() < dirty.len());
+        assert!(db.delete_map.is_empty());
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some("x".repeat(100)));
+        assert_eq!(db.get(&"c".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"d".to_string()).unwrap(), Some("x".repeat(100)));
+    }
+
+    #[test]
+    fn record_header_is_big_endian() {
+        remove_db("big_endian");
+        let mut db: OnDisk<String, u64> = OnDisk::open("big_endian").unwrap();
+        db.put("key".to_string(), 7).unwrap();
+
+        let mut bytes = vec![];
+        db.curr_file().unwrap().read_to_end(&mut bytes).unwrap();
+        let key_len = bincode::serialized_size(&"key".to_string()).unwrap();
+        let value_len = bincode::serialized_size(&7u64).unwrap();
+
+        assert_eq!(bytes.len() as u64, HEADER_LEN + key_len + value_len);
+        assert_eq!(bytes[4], 0);
+        assert_eq!(bytes[5..9], 0u32.to_be_bytes());
+        assert_eq!(bytes[9..17], 0u64.to_be_bytes());
+        assert_eq!(
+            bytes[17..25],
+            db.timestamp(&"key".to_string())
+                .unwrap()
+                .unwrap()
+                .to_be_bytes()
+        );
+        assert_eq!(bytes[25..33], 0u64.to_be_bytes());
+        assert_eq!(bytes[33..41], key_len.to_be_bytes());
+        assert_eq!(bytes[41..49], value_len.to_be_bytes());
+        let checksum = Crc::<u32>::new(&CRC_32_CKSUM).checksum(&bytes[4..]);
+        assert_eq!(bytes[..4], checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn iter_by_sequence_follows_write_order() {
+        remove_db("iter_by_sequence");
+        let mut db: OnDisk<String, u64> = OnDisk::open("iter_by_sequence").unwrap();
+        db.put("c".to_string(), 1).unwrap();
+        db.put("a".to_string(), 2).unwrap();
+        db.put("b".to_string(), 3).unwrap();
+        db.put("a".to_string(), 4).unwrap();
+        db.put("d".to_string(), 5).unwrap();
+        db.delete(&"d".to_string()).unwrap();
+
+        let expected = vec![
+            ("c".to_string(), 1),
+            ("b".to_string(), 3),
+            ("a".to_string(), 4),
+        ];
+        let entries: Vec<_> = db.iter_by_sequence().map(|e| e.unwrap()).collect();
+        assert_eq!(entries, expected);
+
+        // copying records during compaction keeps their order
+        db.prune().unwrap();
+        let entries: Vec<_> = db.iter_by_sequence().map(|e| e.unwrap()).collect();
+        assert_eq!(entries, expected);
+    }
+
+    /// Reads like the wrapped cursor but fails once it passes `fail_at`.
+    struct Interrupted {
+        inner: Cursor<Vec<u8>>,
+        fail_at: u64,
+    }
+
+    impl Read for Interrupted {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.inner.position() >= self.fail_at {
+                return Err(std::io::Error::other("interrupted"));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for Interrupted {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn import_resumes_from_checkpoint() {
+        let checkpoint = "import_resumable.checkpoint";
+        remove_db("import_resumable");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("import_resumable").unwrap();
+
+        let mut input = vec![];
+        for i in 0..2500u64 {
+            bincode::serialize_into(&mut input, &(i, i * 2)).unwrap();
+        }
+        let record_len = input.len() as u64 / 2500;
+
+        let interrupted = Interrupted {
+            inner: Cursor::new(input.clone()),
+            fail_at: record_len * 1800,
+        };
+        assert!(db.import_resumable(interrupted, checkpoint).is_err());
+        assert_eq!(db.keys().unwrap().len(), 1800);
+        assert!(fs::exists(checkpoint).unwrap());
+
+        // records before the checkpoint aren't read again, so changing them
+        // in the input has no effect
+        let mut resumed = bincode::serialize(&(0u64, 999u64)).unwrap();
+        resumed.extend_from_slice(&input[record_len as usize..]);
+        db.import_resumable(Cursor::new(resumed), checkpoint)
+            .unwrap();
+        assert_eq!(db.keys().unwrap().len(), 2500);
+        for i in 0..2500u64 {
+            assert_eq!(db.get(&i).unwrap(), Some(i * 2));
+        }
+        assert!(!fs::exists(checkpoint).unwrap());
+    }
+
+    #[test]
+    fn byte_range_covers_record() {
+        remove_db("byte_range");
+        let mut db: OnDisk<String, String> = OnDisk::open("byte_range").unwrap();
+        db.put("a".to_string(), "first".to_string()).unwrap();
+        db.put("b".to_string(), "second".to_string()).unwrap();
+
+        let a_len = HEADER_LEN
+            + bincode::serialized_size(&"a".to_string()).unwrap()
+            + bincode::serialized_size(&"first".to_string()).unwrap();
+        assert_eq!(
+            db.byte_range(&"a".to_string()).unwrap(),
+            Some((1, 0, a_len))
+        );
+
+        let (file_id, start, end) = db.byte_range(&"b".to_string()).unwrap().unwrap();
+        assert_eq!((file_id, start), (1, a_len));
+        let mut record = vec![0u8; (end - start) as usize];
+        let mut file = db.curr_file().unwrap();
+        file.seek(SeekFrom::Start(start)).unwrap();
+        file.read_exact(&mut record).unwrap();
+        let value = bincode::serialize(&"second".to_string()).unwrap();
+        assert!(record.ends_with(&value));
+
+        assert_eq!(db.byte_range(&"c".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn soft_delete_restores_until_prune() {
+        remove_db("soft_delete");
+        let mut db: OnDisk<String, String> = OnDisk::open("soft_delete").unwrap();
+        db.put("a".to_string(), "kept".to_string()).unwrap();
+        db.put("b".to_string(), "other".to_string()).unwrap();
+
+        db.soft_delete(&"a".to_string()).unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), None);
+        // a regular delete frees its slot, which must not land on the record
+        db.delete(&"b".to_string()).unwrap();
+        db.put("c".to_string(), "other".to_string()).unwrap();
+        assert!(db.restore(&"a".to_string()).unwrap());
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some("kept".to_string()));
+        assert!(!db.restore(&"b".to_string()).unwrap());
+
+        db.soft_delete(&"a".to_string()).unwrap();
+        db.prune().unwrap();
+        assert!(!db.restore(&"a".to_string()).unwrap());
+        assert_eq!(db.get(&"a".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn spilled_entries_stay_readable() {
+        remove_db("spill");
+        let mut db: OnDisk<u64, String> = OnDisk::open("spill").unwrap();
+        db.set_key_dir_limit(Some(16)).unwrap();
+        for i in 0..100 {
+            db.put(i, format!("value {}", i)).unwrap();
+            if i % 10 == 9 {
+                db.sync().unwrap();
+            }
+        }
+        assert!(db.key_dir.len() <= 16);
+        for i in 0..100 {
+            assert_eq!(db.get(&i).unwrap(), Some(format!("value {}", i)));
+        }
+        assert_eq!(db.get(&100).unwrap(), None);
+
+        db.put(3, "changed".to_string()).unwrap();
+        db.delete(&4).unwrap();
+        assert_eq!(db.get(&3).unwrap(), Some("changed".to_string()));
+        assert_eq!(db.get(&4).unwrap(), None);
+        assert_eq!(
+            db.value_len(&50).unwrap(),
+            Some(bincode::serialized_size("value 50").unwrap() as usize)
+        );
+
+        db.prune().unwrap();
+        assert!(db.key_dir.len() <= 16);
+        assert_eq!(db.get(&3).unwrap(), Some("changed".to_string()));
+        assert_eq!(db.get(&4).unwrap(), None);
+        assert_eq!(db.keys().unwrap().len(), 99);
+    }
+
+    #[test]
+    fn validate_flags_corruption() {
+        remove_db("validate");
+        let mut db: OnDisk<String, String> = OnDisk::open("validate").unwrap();
+        db.put("a".to_string(), "x".repeat(100)).unwrap();
+        db.put("b".to_string(), "y".repeat(10)).unwrap();
+        db.delete(&"a".to_string()).unwrap();
+        // reuses the freed larger slot and pads the rest with a filler
+        db.put("a".to_string(), "z".to_string()).unwrap();
+        db.delete(&"b".to_string()).unwrap();
+        db.sync().unwrap();
+        drop(db);
+
+        let report = validate("validate").unwrap();
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!((report.files, report.records), (2, 4));
+
+        let mut bytes = fs::read("validate.1.db").unwrap();
+        bytes[HEADER_LEN as usize + 4] ^= 0xff;
+        fs::write("validate.1.db", bytes).unwrap();
+        let report = validate("validate").unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].contains("checksum mismatch"));
+    }
+
+    #[test]
+    fn threaded_prune_matches_single_threaded() {
+        let mut pruned = vec![];
+        for (prefix, threads) in [("prune_single", 1), ("prune_threaded", 4)] {
+            remove_db(prefix);
+            let mut db: OnDisk<u64, String> = OnDisk::open(prefix).unwrap();
+            db.set_compaction_threads(threads);
+            for i in 0..50 {
+                db.put(i % 30, format!("value {}", i)).unwrap();
+                if i % 10 == 9 {
+                    db.sync().unwrap();
+                }
+            }
+            db.delete(&7).unwrap();
+            db.prune().unwrap();
+
+            // the same records in the same places, written at different times
+            let files: Vec<_> = data_file_ids(prefix)
+                .unwrap()
+                .into_iter()
+                .map(|f_id| {
+                    let path = format!("{}.{}.db", prefix, f_id);
+                    let reader = open_single_file::<u64, String>(&path).unwrap();
+                    reader
+                        .iter()
+                        .map(|record| FileRecord {
+                            timestamp: 0,
+                            ..record.unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            pruned.push((
+                db.items()
+                    .unwrap()
+                    .into_iter()
+                    .map(|(k, v)| (*k, v))
+                    .collect::<Vec<_>>(),
+                files,
+            ));
+        }
+        assert_eq!(pruned[0], pruned[1]);
+        assert_eq!(pruned[0].0.len(), 29);
+    }
+
+    #[test]
+    fn recommended_file_count_rounds_up() {
+        assert_eq!(recommended_file_count(0, 1024), 1);
+        assert_eq!(recommended_file_count(1, 1024), 1);
+        assert_eq!(recommended_file_count(1024, 1024), 1);
+        assert_eq!(recommended_file_count(1025, 1024), 2);
+        assert_eq!(recommended_file_count(10 * 1024 + 1, 1024), 11);
+        assert_eq!(recommended_file_count(u64::MAX, 1), u64::MAX);
+        assert_eq!(recommended_file_count(5000, 0), 1);
+
+        remove_db("file_counts");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("file_counts").unwrap();
+        for i in 0..3 {
+            db.put(i, i).unwrap();
+            db.sync().unwrap();
+        }
+        let record_len = db
+            .byte_range(&0)
+            .unwrap()
+            .map(|(_, start, end)| end - start)
+            .unwrap();
+        assert_eq!(db.file_counts(record_len).unwrap(), (4, 3));
+        assert_eq!(db.file_counts(3 * record_len).unwrap(), (4, 1));
+    }
+
+    #[test]
+    fn namespaces_lists_key_prefixes() {
+        remove_db("namespaces");
+        let mut db: OnDisk<String, u64> = OnDisk::open("namespaces").unwrap();
+        for key in ["users:1", "users:2", "orders:1", "plain", "tenants:a:b"] {
+            db.put(key.to_string(), 0).unwrap();
+        }
+        db.delete(&"orders:1".to_string()).unwrap();
+        assert_eq!(
+            db.namespaces().unwrap(),
+            vec!["tenants".to_string(), "users".to_string()]
+        );
+    }
+
+    #[test]
+    fn prune_compresses_cold_files() {
+        remove_db("compress_on_prune");
+        let mut db: OnDisk<u64, String> = OnDisk::open("compress_on_prune").unwrap();
+        db.set_compress_on_prune(true);
+        for i in 0..20 {
+            db.put(i, format!("{} ", i).repeat(200)).unwrap();
+            if i % 10 == 9 {
+                db.sync().unwrap();
+            }
+        }
+        let file_len = |f_id| {
+            fs::metadata(format!("compress_on_prune.{}.db", f_id))
+                .unwrap()
+                .len()
+        };
+        let before = file_len(1) + file_len(2);
+        db.put(20, "new ".repeat(200)).unwrap();
+        db.prune().unwrap();
+
+        assert!(file_len(1) + file_len(2) < before / 4);
+        for i in 0..20 {
+            assert_eq!(db.get(&i).unwrap(), Some(format!("{} ", i).repeat(200)));
+        }
+        // the active file was not compressed
+        let serialized_len = bincode::serialized_size(&"new ".repeat(200)).unwrap() as usize;
+        assert_eq!(db.value_len(&20).unwrap(), Some(serialized_len));
+        assert_eq!(db.get(&20).unwrap(), Some("new ".repeat(200)));
+        db.put(21, "newer".to_string()).unwrap();
+        assert_eq!(db.get(&21).unwrap(), Some("newer".to_string()));
+        drop(db);
+        assert!(validate("compress_on_prune").unwrap().is_ok());
+    }
+
+    #[test]
+    fn snapshot_reads_values_from_before_writes() {
+        remove_db("snapshot");
+        let mut db: OnDisk<String, u64> = OnDisk::open("snapshot").unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        db.put("b".to_string(), 2).unwrap();
+        let a_range = db.byte_range(&"a".to_string()).unwrap().unwrap();
+
+        let snapshot = db.snapshot().unwrap();
+        db.delete(&"a".to_string()).unwrap();
+        // same size as "a", so it would land on its record if the slot were free
+        db.put("c".to_string(), 3).unwrap();
+        db.put("b".to_string(), 20).unwrap();
+        assert!(db.prune().is_err());
+
+        let items: Vec<_> = db.iter_snapshot(&snapshot).map(Result::unwrap).collect();
+        assert_eq!(items, vec![("a".to_string(), 1), ("b".to_string(), 2)]);
+        assert_ne!(db.byte_range(&"c".to_string()).unwrap(), Some(a_range));
+
+        drop(snapshot);
+        db.delete(&"c".to_string()).unwrap();
+        let ranges: Vec<_> = ["d", "e", "f"]
+            .into_iter()
+            .map(|key| {
+                db.put(key.to_string(), 4).unwrap();
+                db.byte_range(&key.to_string()).unwrap().unwrap()
+            })
+            .collect();
+        assert!(ranges.contains(&a_range));
+        assert!(db.prune().is_ok());
+    }
+
+    #[test]
+    fn append_to_list_extends_stored_list() {
+        remove_db("append_to_list");
+        let mut db: OnDisk<String, Vec<u64>> = OnDisk::open("append_to_list").unwrap();
+        for i in 0..5 {
+            db.append_to_list("list".to_string(), i).unwrap();
+        }
+        db.append_to_list("other".to_string(), 9).unwrap();
+        assert_eq!(
+            db.get(&"list".to_string()).unwrap(),
+            Some(vec![0, 1, 2, 3, 4])
+        );
+        assert_eq!(db.get(&"other".to_string()).unwrap(), Some(vec![9]));
+    }
+
+    #[test]
+    fn single_file_reader_lists_dead_records() {
+        remove_db("single_file");
+        let mut db: OnDisk<String, u64> = OnDisk::open("single_file").unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        db.put("b".to_string(), 2).unwrap();
+        db.put("a".to_string(), 3).unwrap();
+        db.delete(&"b".to_string()).unwrap();
+        let a_range = db.byte_range(&"a".to_string()).unwrap().unwrap();
+        drop(db);
+
+        let reader: SingleFileReader<String, u64> = open_single_file("single_file.1.db").unwrap();
+        assert_eq!(reader.corruption(), None);
+        let records: Vec<_> = reader.iter().map(Result::unwrap).collect();
+        let items: Vec<_> = records
+            .iter()
+            .map(|record| (record.key.as_str(), record.value, record.seq))
+            .collect();
+        // the overwrite of "a" went after its old record, and deleting "b"
+        // left a tombstone at the end
+        assert_eq!(
+            items,
+            vec![
+                ("a", Some(1), 0),
+                ("b", Some(2), 1),
+                ("a", Some(3), 2),
+                ("b", None, 3)
+            ]
+        );
+        assert_eq!((1, records[2].start, records[2].end), a_range);
+        assert!(reader.record(4).is_err());
+    }
+
+    #[test]
+    fn read_ahead_cuts_disk_reads() {
+        remove_db("read_ahead");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("read_ahead").unwrap();
+        for i in 0..100 {
+            db.put(i, i * 2).unwrap();
+        }
+
+        let before = db.disk_reads();
+        let values = db.values().unwrap();
+        let unbuffered = db.disk_reads() - before;
+        assert_eq!(unbuffered, 100);
+
+        db.set_read_ahead(4096);
+        let before = db.disk_reads();
+        assert_eq!(db.values().unwrap(), values);
+        let buffered = db.disk_reads() - before;
+        assert!(buffered < 5, "{} reads", buffered);
+
+        // writes are not hidden by the buffer
+        db.put(50, 7).unwrap();
+        assert_eq!(db.get(&50).unwrap(), Some(7));
+        assert_eq!(db.get(&51).unwrap(), Some(102));
+    }
+
+    #[test]
+    fn adjacent_free_slots_merge() {
+        remove_db("merge_free_slots");
+        remove_db("merge_free_slots_reuse");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("merge_free_slots").unwrap();
+        for i in 0..4 {
+            db.put(i, i).unwrap();
+        }
+        let (_, start, _) = db.byte_range(&1).unwrap().unwrap();
+        let (_, _, end) = db.byte_range(&2).unwrap().unwrap();
+        db.delete(&2).unwrap();
+        db.delete(&1).unwrap();
+        assert_eq!(
+            db.free_slots.iter().collect::<Vec<_>>(),
+            vec![&Slot {
+                file_id: 1,
+                start,
+                end
+            }]
+        );
+
+        // too big for either hole on its own
+        let mut db: OnDisk<u64, Vec<u64>> = OnDisk::open("merge_free_slots_reuse").unwrap();
+        for i in 0..4 {
+            db.put(i, vec![i]).unwrap();
+        }
+        let (_, start, _) = db.byte_range(&1).unwrap().unwrap();
+        let (_, _, end) = db.byte_range(&2).unwrap().unwrap();
+        db.delete(&1).unwrap();
+        db.delete(&2).unwrap();
+        db.put(9, vec![9, 9, 9, 9]).unwrap();
+        let (file_id, new_start, new_end) = db.byte_range(&9).unwrap().unwrap();
+        assert_eq!((file_id, new_start), (1, start));
+        assert!(new_end <= end);
+        assert_eq!(db.get(&9).unwrap(), Some(vec![9, 9, 9, 9]));
+        assert_eq!(db.get(&3).unwrap(), Some(vec![3]));
+    }
+
+    #[test]
+    fn key_flags_survive_rewrites() {
+        remove_db("key_flags");
+        let mut db: OnDisk<String, u64> = OnDisk::open("key_flags").unwrap();
+        db.put_with_flags("pinned".to_string(), 1, 0b101).unwrap();
+        db.put("plain".to_string(), 2).unwrap();
+        db.put("gone".to_string(), 3).unwrap();
+        db.delete(&"gone".to_string()).unwrap();
+        assert_eq!(db.flags_of(&"pinned".to_string()).unwrap(), Some(0b101));
+        assert_eq!(db.flags_of(&"plain".to_string()).unwrap(), Some(0));
+        assert_eq!(db.flags_of(&"gone".to_string()).unwrap(), None);
+
+        db.prune().unwrap();
+        assert_eq!(db.flags_of(&"pinned".to_string()).unwrap(), Some(0b101));
+        assert_eq!(db.get(&"pinned".to_string()).unwrap(), Some(1));
+        drop(db);
+
+        let reader: SingleFileReader<String, u64> = open_single_file("key_flags.1.db").unwrap();
+        let flags: Vec<_> = reader
+            .iter()
+            .map(|record| {
+                let record = record.unwrap();
+                (record.key, record.key_flags)
+            })
+            .collect();
+        assert_eq!(
+            flags,
+            vec![("pinned".to_string(), 0b101), ("plain".to_string(), 0)]
+        );
+    }
+
+    #[test]
+    fn keys_owned_allows_deleting_while_iterating() {
+        remove_db("keys_owned");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("keys_owned").unwrap();
+        for i in 0..10 {
+            db.put(i, i).unwrap();
+        }
+        for key in db.keys_owned().unwrap() {
+            if key % 2 == 0 {
+                db.delete(&key).unwrap();
+            }
+        }
+        assert_eq!(db.keys_owned().unwrap(), vec![1, 3, 5, 7, 9]);
+    }
+
+    #[test]
+    fn tombstones_outlive_file_rewrites() {
+        remove_db("tombstones");
+        let mut db: OnDisk<String, u64> = OnDisk::open("tombstones").unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        db.put("b".to_string(), 2).unwrap();
+        db.sync().unwrap();
+        db.put("c".to_string(), 3).unwrap();
+        db.delete(&"a".to_string()).unwrap();
+        db.put("c".to_string(), 4).unwrap();
+        // rewriting drops the dead records but keeps the tombstone, as only a
+        // prune knows no older record of "a" is left anywhere
+        db.compact_deletes().unwrap();
+
+        let tombstones = |f_id| {
+            let reader: SingleFileReader<String, u64> =
+                open_single_file(&format!("tombstones.{}.db", f_id)).unwrap();
+            reader
+                .iter()
+                .map(Result::unwrap)
+                .filter(|record| record.value.is_none())
+                .map(|record| record.key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tombstones(2), vec!["a".to_string()]);
+
+        // a prune drops every dead record, so the tombstone can go too
+        db.prune().unwrap();
+        assert!(tombstones(2).is_empty());
+        assert_eq!(db.get(&"a".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"c".to_string()).unwrap(), Some(4));
+        db.put("d".to_string(), 5).unwrap();
+        drop(db);
+        assert!(validate("tombstones").unwrap().is_ok());
+    }
+
+    #[test]
+    fn cursor_steps_both_ways() {
+        remove_db("cursor");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("cursor").unwrap();
+        for i in [10, 20, 30] {
+            db.put(i, i * 2).unwrap();
+        }
+        let mut cursor = db.cursor().unwrap();
+        assert!(cursor.seek(&15));
+        assert_eq!(cursor.key(), Some(&20));
+        assert_eq!(cursor.value().unwrap(), Some(40));
+        assert!(cursor.next());
+        assert_eq!(cursor.key(), Some(&30));
+        assert!(!cursor.next());
+        assert_eq!(cursor.key(), None);
+        assert!(cursor.prev());
+        assert_eq!(cursor.key(), Some(&30));
+        assert!(cursor.prev());
+        assert!(cursor.prev());
+        assert_eq!(cursor.value().unwrap(), Some(20));
+        assert!(!cursor.prev());
+        assert!(!cursor.seek(&31));
+    }
+
+    #[test]
+    fn value_middleware_transforms_values() {
+        remove_db("middleware");
+        let mut db: OnDisk<String, u64> = OnDisk::open("middleware").unwrap();
+        db.set_value_middleware(|v| v * 2, |v| v + 1);
+        db.put("a".to_string(), 5).unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(11));
+
+        // the stored bytes hold the doubled value
+        let reader: SingleFileReader<String, u64> = open_single_file("middleware.1.db").unwrap();
+        let record = reader.record(0).unwrap();
+        assert_eq!(record.value, Some(10));
+
+        // compaction keeps values as stored rather than transforming again
+        db.prune().unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(11));
+    }
+
+    #[test]
+    fn neighbouring_keys_stop_at_the_ends() {
+        remove_db("neighbours");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("neighbours").unwrap();
+        for i in [1, 5, 9] {
+            db.put(i, i).unwrap();
+        }
+        assert_eq!(db.next_key(&1).unwrap(), Some(5));
+        assert_eq!(db.next_key(&6).unwrap(), Some(9));
+        assert_eq!(db.next_key(&9).unwrap(), None);
+        assert_eq!(db.prev_key(&9).unwrap(), Some(5));
+        assert_eq!(db.prev_key(&4).unwrap(), Some(1));
+        assert_eq!(db.prev_key(&1).unwrap(), None);
+    }
+
+    #[test]
+    fn neighbouring_keys_include_spilled_ones() {
+        remove_db("neighbours_spilled");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("neighbours_spilled").unwrap();
+        db.set_key_dir_limit(Some(40)).unwrap();
+        for i in 0..200 {
+            db.put(i * 2, i).unwrap();
+            if i % 10 == 9 {
+                db.sync().unwrap();
+            }
+        }
+        // one spilled key back in memory, one gone
+        db.put(100, 0).unwrap();
+        db.delete(&102).unwrap();
+        assert!(db.spill_len > SPILL_BLOCK_LEN);
+
+        let keys = db.keys_owned().unwrap();
+        for probe in 0..402 {
+            let next = keys.iter().find(|key| **key > probe).copied();
+            let prev = keys.iter().rev().find(|key| **key < probe).copied();
+            assert_eq!(db.next_key(&probe).unwrap(), next, "after {}", probe);
+            assert_eq!(db.prev_key(&probe).unwrap(), prev, "before {}", probe);
+        }
+    }
+
+    #[test]
+    fn transactions_commit_or_roll_back() {
+        remove_db("transaction");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("transaction").unwrap();
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+
+        let mut txn = db.begin();
+        txn.put(1, 11);
+        txn.delete(&2);
+        txn.put(3, 30);
+        assert_eq!(txn.get(&1).unwrap(), Some(11));
+        assert_eq!(txn.get(&2).unwrap(), None);
+        txn.rollback();
+        assert_eq!(db.items().unwrap(), vec![(&1, 10), (&2, 20)]);
+
+        let mut txn = db.begin();
+        txn.put(1, 11);
+        txn.delete(&2);
+        txn.put(3, 30);
+        txn.commit().unwrap();
+        assert_eq!(db.items().unwrap(), vec![(&1, 11), (&3, 30)]);
+    }
+
+    #[test]
+    fn compact_file_leaves_other_files_alone() {
+        use std::os::unix::fs::MetadataExt;
+
+        remove_db("compact_file");
+        let mut db: OnDisk<u64, String> = OnDisk::open("compact_file").unwrap();
+        for i in 0..4 {
+            db.put(i, "x".repeat(100)).unwrap();
+        }
+        db.sync().unwrap();
+        db.put(10, "y".repeat(100)).unwrap();
+        db.sync().unwrap();
+        for i in 0..3 {
+            db.delete(&i).unwrap();
+        }
+
+        let fragmented = fs::metadata("compact_file.1.db").unwrap().len();
+        let other = fs::metadata("compact_file.2.db").unwrap();
+        let reclaimed = db.compact_file(1).unwrap();
+
+        let compacted = fs::metadata("compact_file.1.db").unwrap().len();
+        assert!(reclaimed > 0);
+        assert_eq!(compacted, fragmented - reclaimed);
+        assert_eq!(
+            fs::metadata("compact_file.2.db").unwrap().ino(),
+            other.ino()
+        );
+        assert_eq!(db.get(&3).unwrap(), Some("x".repeat(100)));
+        assert_eq!(db.get(&10).unwrap(), Some("y".repeat(100)));
+        assert_eq!(db.get(&0).unwrap(), None);
+        assert!(db.compact_file(9).is_err());
+    }
+
+    #[test]
+    fn get_as_reads_a_compatible_type() {
+        remove_db("get_as");
+        remove_db("get_as_json");
+        #[derive(Serialize, Deserialize)]
+        struct Point {
+            x: u32,
+            y: u32,
+        }
+
+        let mut db: OnDisk<String, Point, JsonCodec> = OnDisk::open("get_as_json").unwrap();
+        db.put("p".to_string(), Point { x: 3, y: 4 }).unwrap();
+        assert_eq!(
+            db.get_as::<serde_json::Value>(&"p".to_string()).unwrap(),
+            Some(serde_json::json!({ "x": 3, "y": 4 }))
+        );
+        assert_eq!(
+            db.get_as::<serde_json::Value>(&"q".to_string()).unwrap(),
+            None
+        );
+
+        let mut db: OnDisk<String, Point> = OnDisk::open("get_as").unwrap();
+        db.put("p".to_string(), Point { x: 3, y: 4 }).unwrap();
+        assert_eq!(
+            db.get_as::<(u32, u32)>(&"p".to_string()).unwrap(),
+            Some((3, 4))
+        );
+        assert_eq!(db.get_as::<(u32, u32)>(&"q".to_string()).unwrap(), None);
+        assert!(db.get_as::<(u64, u64)>(&"p".to_string()).is_err());
+    }
+
+    #[test]
+    fn clean_shutdown_is_recorded_on_drop() {
+        remove_db("clean_shutdown");
+        let db: OnDisk<u64, u64> = OnDisk::open("clean_shutdown").unwrap();
+        assert!(!was_clean_shutdown("clean_shutdown").unwrap());
+        drop(db);
+        assert!(was_clean_shutdown("clean_shutdown").unwrap());
+
+        let mut db: OnDisk<u64, u64> = OnDisk::open("clean_shutdown").unwrap();
+        assert!(!was_clean_shutdown("clean_shutdown").unwrap());
+        db.put(1, 1).unwrap();
+        // a crash never runs the destructor
+        std::mem::forget(db);
+        assert!(!was_clean_shutdown("clean_shutdown").unwrap());
+    }
+
+    #[test]
+    fn write_coalescing_drops_rapid_overwrites() {
+        remove_db("coalesce");
+        let mut db: OnDisk<String, u64> = OnDisk::open("coalesce").unwrap();
+        db.set_write_coalescing(Some(16)).unwrap();
+        db.put("counter".to_string(), 0).unwrap();
+        for i in 1..=100 {
+            db.put("counter".to_string(), i).unwrap();
+            assert_eq!(db.get(&"counter".to_string()).unwrap(), Some(i));
+        }
+        db.sync().unwrap();
+
+        let reader: SingleFileReader<String, u64> = open_single_file("coalesce.1.db").unwrap();
+        assert!(reader.len() < 10);
+        let last = reader.record(reader.len() - 1).unwrap();
+        assert_eq!(last.value, Some(100));
+        assert_eq!(db.get(&"counter".to_string()).unwrap(), Some(100));
+    }
+
+    #[test]
+    fn dangling_keys_finds_truncated_records() {
+        remove_db("dangling");
+        let mut db: OnDisk<u64, String> = OnDisk::open("dangling").unwrap();
+        for i in 0..3 {
+            db.put(i, "x".repeat(20)).unwrap();
+        }
+        assert!(db.dangling_keys().unwrap().is_empty());
+
+        let (_, start, _) = db.byte_range(&2).unwrap().unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open("dangling.1.db")
+            .unwrap()
+            .set_len(start + 1)
+            .unwrap();
+        assert_eq!(db.dangling_keys().unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn key_normalizer_folds_case() {
+        remove_db("normalizer");
+        let mut db: OnDisk<String, u64> = OnDisk::open("normalizer").unwrap();
+        db.set_key_normalizer(|key: &String| key.to_lowercase());
+        db.put("Foo".to_string(), 1).unwrap();
+        assert_eq!(db.get(&"foo".to_string()).unwrap(), Some(1));
+        db.put("FOO".to_string(), 2).unwrap();
+        assert_eq!(db.keys_owned().unwrap(), vec!["foo".to_string()]);
+        assert_eq!(db.get(&"fOo".to_string()).unwrap(), Some(2));
+        db.delete(&"Foo".to_string()).unwrap();
+        assert_eq!(db.get(&"foo".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn export_snapshot_ignores_later_writes() {
+        remove_db("export_source");
+        remove_db("export_copy");
+        let mut db: OnDisk<u64, String> = OnDisk::open("export_source").unwrap();
+        for i in 0..20 {
+            db.put(i, format!("value {}", i)).unwrap();
+        }
+        let snapshot = db.snapshot().unwrap();
+        for i in 0..20 {
+            db.put(i, format!("overwritten {}", i)).unwrap();
+        }
+        db.delete(&3).unwrap();
+        db.put(100, "new".to_string()).unwrap();
+
+        let mut exported = vec![];
+        db.export_from_snapshot(&snapshot, &mut exported).unwrap();
+        drop(snapshot);
+
+        let mut copy: OnDisk<u64, String> = OnDisk::open("export_copy").unwrap();
+        copy.import_resumable(Cursor::new(exported), "export_copy.checkpoint")
+            .unwrap();
+        let expected: Vec<_> = (0..20).map(|i| (i, format!("value {}", i))).collect();
+        let items: Vec<_> = copy
+            .items()
+            .unwrap()
+            .into_iter()
+            .map(|(k, v)| (*k, v))
+            .collect();
+        assert_eq!(items, expected);
+
+        let mut exported = vec![];
+        db.export_snapshot(&mut exported).unwrap();
+        assert!(!db.snapshots_open());
+        remove_db("export_copy");
+        let mut copy: OnDisk<u64, String> = OnDisk::open("export_copy").unwrap();
+        copy.import_resumable(Cursor::new(exported), "export_copy.checkpoint")
+            .unwrap();
+        assert_eq!(copy.len(), 20);
+        assert_eq!(copy.get(&3).unwrap(), None);
+        assert_eq!(copy.get(&4).unwrap(), Some("overwritten 4".to_string()));
+        assert_eq!(copy.get(&100).unwrap(), Some("new".to_string()));
+    }
+
+    #[test]
+    fn size_budget_evicts_least_recently_used() {
+        remove_db("lru_budget");
+        let mut db: OnDisk<String, String> = OnDisk::open("lru_budget").unwrap();
+        let record_len = HEADER_LEN
+            + bincode::serialized_size(&"a".to_string()).unwrap()
+            + bincode::serialized_size(&"x".repeat(20)).unwrap();
+        db.set_max_total_bytes(Some(3 * record_len + 10)).unwrap();
+        for key in ["a", "b", "c"] {
+            db.put(key.to_string(), "x".repeat(20)).unwrap();
+        }
+        db.get(&"a".to_string()).unwrap();
+        db.put("d".to_string(), "x".repeat(20)).unwrap();
+
+        assert_eq!(
+            db.keys_owned().unwrap(),
+            vec!["a".to_string(), "c".to_string(), "d".to_string()]
+        );
+    }
+
+    #[test]
+    fn get_verified_reports_where_corruption_is() {
+        remove_db("get_verified");
+        let mut db: OnDisk<u64, String> = OnDisk::open("get_verified").unwrap();
+        db.set_read_ahead(4096);
+        db.put(1, "good".to_string()).unwrap();
+        db.put(2, "soon bad".to_string()).unwrap();
+        assert_eq!(db.get_verified(&2).unwrap(), Some("soon bad".to_string()));
+        assert_eq!(db.get_verified(&3).unwrap(), None);
+        assert_eq!(db.get(&2).unwrap(), Some("soon bad".to_string()));
+
+        let (file_id, start, end) = db.byte_range(&2).unwrap().unwrap();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open("get_verified.1.db")
+            .unwrap();
+        file.seek(SeekFrom::Start(end - 1)).unwrap();
+        file.write_all(b"!").unwrap();
+        // get is served the copy the read-ahead buffer kept
+        assert_eq!(db.get(&2).unwrap(), Some("soon bad".to_string()));
+
+        let err = db.get_verified(&2).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ChecksumMismatch>(),
+            Some(&ChecksumMismatch {
+                file_id,
+                offset: start
+            })
+        );
+        assert_eq!(db.get_verified(&1).unwrap(), Some("good".to_string()));
+    }
+
+    #[test]
+    fn record_separators_resync_after_corruption() {
+        remove_db("separators");
+        let mut db: OnDisk<u64, String> = OnDisk::open("separators").unwrap();
+        db.set_record_separators(true);
+        for i in 0..3 {
+            db.put(i, format!("value {}", i)).unwrap();
+        }
+        let (_, start, _) = db.byte_range(&1).unwrap().unwrap();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open("separators.1.db")
+            .unwrap();
+        file.seek(SeekFrom::Start(start + HEADER_LEN)).unwrap();
+        file.write_all(b"!").unwrap();
+
+        let reader: SingleFileReader<u64, String> = open_single_file("separators.1.db").unwrap();
+        assert!(reader.corruption().is_some());
+        let keys: Vec<_> = reader.iter().map(|record| record.unwrap().key).collect();
+        assert_eq!(keys, vec![0, 2]);
+        assert_eq!(db.get(&2).unwrap(), Some("value 2".to_string()));
+    }
+
+    #[test]
+    fn live_value_bytes_sums_values() {
+        remove_db("live_value_bytes");
+        let mut db: OnDisk<u64, String> = OnDisk::open("live_value_bytes").unwrap();
+        let mut expected = 0;
+        for i in 0..10 {
+            let value = "x".repeat(i * 10);
+            expected += bincode::serialized_size(&value).unwrap();
+            db.put(i as u64, value).unwrap();
+        }
+        db.put(0, "overwritten".to_string()).unwrap();
+        expected += bincode::serialized_size("overwritten").unwrap();
+        expected -= bincode::serialized_size("").unwrap();
+        db.delete(&1).unwrap();
+        expected -= bincode::serialized_size(&"x".repeat(10)).unwrap();
+        assert_eq!(db.live_value_bytes().unwrap(), expected);
+    }
+
+    #[test]
+    fn strict_durability_hides_writes_until_sync() {
+        remove_db("strict_durability");
+        let mut db: OnDisk<String, u64> = OnDisk::open("strict_durability").unwrap();
+        db.set_strict_durability(true).unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), None);
+        db.sync().unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(1));
+
+        db.put("a".to_string(), 2).unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(1));
+        db.sync().unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(2));
+
+        db.delete(&"a".to_string()).unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(2));
+        db.sync().unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), None);
+        db.prune().unwrap();
+        assert!(validate("strict_durability").unwrap().is_ok());
+    }
+
+    #[test]
+    fn open_recovers_the_key_dir() {
+        remove_db("recover");
+        let mut db: OnDisk<String, String> = OnDisk::open("recover").unwrap();
+        db.put("a".to_string(), "first".to_string()).unwrap();
+        db.put("b".to_string(), "x".repeat(50)).unwrap();
+        db.put("c".to_string(), "gone".to_string()).unwrap();
+        db.sync().unwrap();
+        db.put("a".to_string(), "second".to_string()).unwrap();
+        db.delete(&"b".to_string()).unwrap();
+        // lands in the space "b" left in the first file, after the tombstone
+        // of "b" in the second
+        db.put("d".to_string(), "reused".to_string()).unwrap();
+        db.delete(&"c".to_string()).unwrap();
+        db.put_with_flags("e".to_string(), "flagged".to_string(), 7)
+            .unwrap();
+        drop(db);
+
+        let mut db: OnDisk<String, String> = OnDisk::open("recover").unwrap();
+        assert_eq!(
+            db.keys_owned().unwrap(),
+            vec!["a".to_string(), "d".to_string(), "e".to_string()]
+        );
+        assert_eq!(
+            db.get(&"a".to_string()).unwrap(),
+            Some("second".to_string())
+        );
+        assert_eq!(
+            db.get(&"d".to_string()).unwrap(),
+            Some("reused".to_string())
+        );
+        assert_eq!(db.flags_of(&"e".to_string()).unwrap(), Some(7));
+
+        // writes after reopening follow on from the recovered state
+        db.put("f".to_string(), "new".to_string()).unwrap();
+        db.put("a".to_string(), "third".to_string()).unwrap();
+        db.prune().unwrap();
+        drop(db);
+        let db: OnDisk<String, String> = OnDisk::open("recover").unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some("third".to_string()));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"c".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"f".to_string()).unwrap(), Some("new".to_string()));
+    }
+
+    #[test]
+    fn get_checks_record_checksums() {
+        remove_db("get_checksum");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("get_checksum").unwrap();
+        db.put(1, 1).unwrap();
+        let (_, _, end) = db.byte_range(&1).unwrap().unwrap();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open("get_checksum.1.db")
+            .unwrap();
+        // the value still deserializes, only the checksum can tell
+        file.seek(SeekFrom::Start(end - 8)).unwrap();
+        file.write_all(&2u64.to_le_bytes()).unwrap();
+        let err = db.get(&1).unwrap_err();
+        assert!(err.downcast_ref::<ChecksumMismatch>().is_some());
+    }
+
+    #[test]
+    fn len_counts_live_keys() {
+        remove_db("len");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("len").unwrap();
+        assert!(db.is_empty());
+        db.set_key_dir_limit(Some(4)).unwrap();
+        for i in 0..20 {
+            db.put(i, i).unwrap();
+            db.sync().unwrap();
+        }
+        db.put(3, 30).unwrap();
+        db.delete(&5).unwrap();
+        db.delete(&100).unwrap();
+        assert_eq!(db.len(), 19);
+        assert!(!db.is_empty());
+        db.prune().unwrap();
+        assert_eq!(db.len(), 19);
+    }
+
+    #[test]
+    fn contains_key_skips_data_files() {
+        remove_db("contains_key");
+        let mut db: OnDisk<u64, String> = OnDisk::open("contains_key").unwrap();
+        db.put(1, "x".repeat(1000)).unwrap();
+        db.put(2, "y".to_string()).unwrap();
+        db.delete(&2).unwrap();
+        let reads = db.disk_reads();
+        assert!(db.contains_key(&1).unwrap());
+        assert!(!db.contains_key(&2).unwrap());
+        assert!(!db.contains_key(&3).unwrap());
+        assert_eq!(db.disk_reads(), reads);
+    }
+
+    #[test]
+    fn max_file_size_rolls_over() {
+        remove_db("max_file_size");
+        let mut db: OnDisk<u64, String> = OnDisk::open("max_file_size").unwrap();
+        db.set_max_file_size(Some(256));
+        for i in 0..10 {
+            db.put(i, "x".repeat(50)).unwrap();
+        }
+        db.put(10, "y".repeat(1000)).unwrap();
+        db.put(11, "z".to_string()).unwrap();
+
+        let (big_file, start, _) = db.byte_range(&10).unwrap().unwrap();
+        assert_eq!(start, 0);
+        let file_ids = data_file_ids("max_file_size").unwrap();
+        assert!(file_ids.len() > 3);
+        for f_id in file_ids {
+            let len = fs::metadata(format!("max_file_size.{}.db", f_id))
+                .unwrap()
+                .len();
+            assert!(len <= 256 || f_id == big_file);
+        }
+        assert_eq!(db.byte_range(&11).unwrap().unwrap().0, big_file + 1);
+        for i in 0..10 {
+            assert_eq!(db.get(&i).unwrap(), Some("x".repeat(50)));
+        }
+    }
+
+    #[test]
+    fn range_scans_a_key_range() {
+        remove_db("range");
+        let mut db: OnDisk<String, u64> = OnDisk::open("range").unwrap();
+        for (i, key) in ["apple", "banana", "cherry", "melon", "nectarine"]
+            .iter()
+            .enumerate()
+        {
+            db.put(key.to_string(), i as u64).unwrap();
+        }
+        let keys = |entries: Vec<(String, u64)>| {
+            entries.into_iter().map(|(key, _)| key).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keys(db.range("b".to_string().."n".to_string()).unwrap()),
+            vec!["banana", "cherry", "melon"]
+        );
+        assert_eq!(
+            db.range("melon".to_string()..).unwrap(),
+            vec![("melon".to_string(), 3), ("nectarine".to_string(), 4)]
+        );
+        assert!(db.range(.."a".to_string()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn iter_reads_values_lazily() {
+        remove_db("lazy_iter");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("lazy_iter").unwrap();
+        for i in (0..10).rev() {
+            db.put(i, i * 3).unwrap();
+        }
+        let reads = db.disk_reads();
+        let mut iter = db.iter();
+        assert_eq!(db.disk_reads(), reads);
+        assert_eq!(iter.next().unwrap().unwrap(), (0, 0));
+        assert_eq!(db.disk_reads(), reads + 1);
+        let rest: Vec<_> = iter.map(Result::unwrap).collect();
+        assert_eq!(rest, (1..10).map(|i| (i, i * 3)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sync_fsyncs_every_written_file() {
+        remove_db("fsync");
+        let mut db: OnDisk<u64, String> = OnDisk::open("fsync").unwrap();
+        db.put(1, "x".repeat(100)).unwrap();
+        db.sync().unwrap();
+        assert!(db.unsynced_files.is_empty());
+
+        db.delete(&1).unwrap();
+        // reuses the space in the first file
+        db.put(2, "y".repeat(100)).unwrap();
+        assert_eq!(db.byte_range(&2).unwrap().unwrap().0, 1);
+        assert_eq!(db.unsynced_files, BTreeSet::from([1, 2]));
+        db.sync().unwrap();
+        assert!(db.unsynced_files.is_empty());
+    }
+
+    #[test]
+    fn reused_slots_give_back_what_is_left() {
+        remove_db("split_slots");
+        let mut db: OnDisk<u64, String> = OnDisk::open("split_slots").unwrap();
+        db.put(0, "x".repeat(500)).unwrap();
+        db.put(1, "keep".to_string()).unwrap();
+        db.sync().unwrap();
+        db.delete(&0).unwrap();
+
+        db.put(2, "small".to_string()).unwrap();
+        let (file_id, _, end) = db.byte_range(&2).unwrap().unwrap();
+        assert_eq!(file_id, 1);
+        db.put(3, "also small".to_string()).unwrap();
+        let (file_id, start, _) = db.byte_range(&3).unwrap().unwrap();
+        assert_eq!((file_id, start), (1, end));
+
+        drop(db);
+        assert!(validate("split_slots").unwrap().is_ok());
+        let db: OnDisk<u64, String> = OnDisk::open("split_slots").unwrap();
+        assert_eq!(db.get(&2).unwrap(), Some("small".to_string()));
+        assert_eq!(db.get(&3).unwrap(), Some("also small".to_string()));
+        assert_eq!(db.get(&0).unwrap(), None);
+    }
+
+    #[test]
+    fn flush_keeps_the_active_file() {
+        let prefix = "flush_keeps_active";
+        remove_db(prefix);
+        let mut db: OnDisk<String, u32> = OnDisk::open(prefix).unwrap();
+        db.set_strict_durability(true).unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), None);
+        let (file_id, file_position) = (db.file_id, db.file_position);
+        db.flush().unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(1));
+        assert_eq!((db.file_id, db.file_position), (file_id, file_position));
+        db.put("b".to_string(), 2).unwrap();
+        assert_eq!(db.file_id, file_id);
+    }
+
+    #[test]
+    fn appends_reuse_the_active_writer() {
+        remove_db("active_writer");
+        let mut db: OnDisk<String, u64> = OnDisk::open("active_writer").unwrap();
+        assert!(db.active_writer.is_none());
+        db.put("a".to_string(), 1).unwrap();
+        assert!(db.active_writer.is_some());
+        db.put("b".to_string(), 2).unwrap();
+        db.sync().unwrap();
+        assert!(db.active_writer.is_none());
+        db.put("a".to_string(), 3).unwrap();
+        db.prune().unwrap();
+        assert!(db.active_writer.is_none());
+        db.put("c".to_string(), 4).unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(3));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some(2));
+        assert_eq!(db.get(&"c".to_string()).unwrap(), Some(4));
+        assert!(validate("active_writer").unwrap().is_ok());
+    }
+
+    #[test]
+    fn put_many_and_delete_many() {
+        remove_db("batch");
+        let mut db: OnDisk<String, u64> = OnDisk::open("batch").unwrap();
+        let keys: Vec<String> = (0..20).map(|i| format!("k{}", i % 10)).collect();
+        db.put_many(keys.iter().cloned().zip(0..)).unwrap();
+        assert_eq!(db.len(), 10);
+        assert_eq!(db.get(&"k3".to_string()).unwrap(), Some(13));
+        db.delete_many(&keys[..5]).unwrap();
+        assert_eq!(db.len(), 5);
+        assert_eq!(db.get(&"k3".to_string()).unwrap(), None);
+        drop(db);
+
+        let db: OnDisk<String, u64> = OnDisk::open("batch").unwrap();
+        assert_eq!(db.len(), 5);
+        assert_eq!(db.get(&"k7".to_string()).unwrap(), Some(17));
+        assert!(validate("batch").unwrap().is_ok());
+    }
+
+    #[test]
+    fn timestamps_survive_prune_and_reopen() {
+        remove_db("timestamps");
+        let before = now_millis();
+        let mut db: OnDisk<String, u64> = OnDisk::open("timestamps").unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        db.put("b".to_string(), 2).unwrap();
+        let stamp = db.timestamp(&"a".to_string()).unwrap().unwrap();
+        assert!(stamp >= before && stamp <= now_millis());
+        assert_eq!(db.timestamp(&"c".to_string()).unwrap(), None);
+
+        db.delete(&"b".to_string()).unwrap();
+        db.prune().unwrap();
+        assert_eq!(db.timestamp(&"a".to_string()).unwrap(), Some(stamp));
+        drop(db);
+
+        let db: OnDisk<String, u64> = OnDisk::open("timestamps").unwrap();
+        assert_eq!(db.timestamp(&"a".to_string()).unwrap(), Some(stamp));
+        assert_eq!(db.timestamp(&"b".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn expired_keys_read_as_missing_and_are_pruned() {
+        remove_db("ttl");
+        let mut db: OnDisk<String, u64> = OnDisk::open("ttl").unwrap();
+        db.put_with_ttl("short".to_string(), 1, Duration::from_millis(20))
+            .unwrap();
+        db.put_with_ttl("long".to_string(), 2, Duration::from_secs(3600))
+            .unwrap();
+        db.put("forever".to_string(), 3).unwrap();
+        assert_eq!(db.get(&"short".to_string()).unwrap(), Some(1));
+        std::thread::sleep(Duration::from_millis(30));
+
+        assert_eq!(db.get(&"short".to_string()).unwrap(), None);
+        assert!(!db.contains_key(&"short".to_string()).unwrap());
+        assert_eq!(db.get(&"long".to_string()).unwrap(), Some(2));
+        assert_eq!(db.len(), 3);
+        db.sync().unwrap();
+        db.prune().unwrap();
+        assert_eq!(db.len(), 2);
+        drop(db);
+
+        let db: OnDisk<String, u64> = OnDisk::open("ttl").unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get(&"long".to_string()).unwrap(), Some(2));
+        assert_eq!(db.get(&"forever".to_string()).unwrap(), Some(3));
+    }
+
+    #[test]
+    fn prune_skips_files_without_a_temp_file() {
+        remove_db("prune_no_temp");
+        let mut db: OnDisk<String, u64> = OnDisk::open("prune_no_temp").unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        db.sync().unwrap();
+        db.put("b".to_string(), 2).unwrap();
+        db.sync().unwrap();
+        db.put("c".to_string(), 3).unwrap();
+        // nothing in the second file survives, so it gets no temp file
+        db.delete(&"b".to_string()).unwrap();
+        db.prune().unwrap();
+
+        for f_id in 1..=4 {
+            assert!(!fs::exists(format!("prune_no_temp.{}.temp.db", f_id)).unwrap());
+        }
+        assert_eq!(fs::metadata("prune_no_temp.2.db").unwrap().len(), 0);
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(1));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"c".to_string()).unwrap(), Some(3));
+    }
+
+    #[test]
+    fn prune_joins_files_and_renumbers() {
+        remove_db("prune_renumber");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("prune_renumber").unwrap();
+        for i in 0..100 {
+            db.put(i % 25, i).unwrap();
+            if i % 10 == 9 {
+                db.sync().unwrap();
+            }
+        }
+        db.delete(&0).unwrap();
+        assert_eq!(data_file_ids("prune_renumber").unwrap().len(), 11);
+        db.prune().unwrap();
+
+        assert_eq!(data_file_ids("prune_renumber").unwrap(), vec![1, 2]);
+        assert_eq!((db.file_id, db.file_position), (2, 0));
+        assert_eq!(db.get(&0).unwrap(), None);
+        assert_eq!(db.get(&7).unwrap(), Some(82));
+        db.put(30, 30).unwrap();
+        drop(db);
+
+        let db: OnDisk<u64, u64> = OnDisk::open("prune_renumber").unwrap();
+        assert_eq!(db.len(), 25);
+        assert_eq!(db.get(&24).unwrap(), Some(99));
+        assert_eq!(db.get(&30).unwrap(), Some(30));
+    }
+
+    #[test]
+    fn open_reads_hint_files_written_by_prune() {
+        remove_db("hints");
+        let mut db: OnDisk<String, u64> = OnDisk::open("hints").unwrap();
+        db.set_dedup_on_prune(true);
+        db.put("a".to_string(), 1).unwrap();
+        db.put("b".to_string(), 1).unwrap();
+        db.put("c".to_string(), 3).unwrap();
+        db.put("e".to_string(), 5).unwrap();
+        db.delete(&"c".to_string()).unwrap();
+        db.prune().unwrap();
+        assert!(fs::exists("hints.1.hint.db").unwrap());
+        assert!(!fs::exists("hints.2.hint.db").unwrap());
+        drop(db);
+
+        let mut db: OnDisk<String, u64> = OnDisk::open("hints").unwrap();
+        assert!(db.hinted_files.contains(&1));
+        // both keys sharing the deduplicated record come back
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(1));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some(1));
+        assert_eq!(db.get(&"c".to_string()).unwrap(), None);
+
+        // a shared record stays put while a key still uses it
+        db.put("b".to_string(), 2).unwrap();
+        assert!(fs::exists("hints.1.hint.db").unwrap());
+        // but reusing space in the file makes its hint stale
+        db.delete(&"e".to_string()).unwrap();
+        db.put("d".to_string(), 4).unwrap();
+        assert!(!fs::exists("hints.1.hint.db").unwrap());
+        drop(db);
+
+        let db: OnDisk<String, u64> = OnDisk::open("hints").unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(1));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some(2));
+        assert_eq!(db.get(&"d".to_string()).unwrap(), Some(4));
+        assert_eq!(db.get(&"e".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn clear_leaves_an_empty_database() {
+        remove_db("clear");
+        let mut db: OnDisk<String, u64> = OnDisk::open("clear").unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        db.sync().unwrap();
+        db.put("b".to_string(), 2).unwrap();
+        db.delete(&"a".to_string()).unwrap();
+        db.clear().unwrap();
+
+        assert!(db.is_empty());
+        assert_eq!(db.get(&"b".to_string()).unwrap(), None);
+        assert_eq!(data_file_ids("clear").unwrap(), vec![1]);
+        assert_eq!((db.file_id, db.file_position), (1, 0));
+        db.put("c".to_string(), 3).unwrap();
+        drop(db);
+
+        let db: OnDisk<String, u64> = OnDisk::open("clear").unwrap();
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.get(&"a".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"c".to_string()).unwrap(), Some(3));
+    }
+
+    #[test]
+    fn shared_db_across_threads() {
+        remove_db("shared");
+        let db: SharedDb<u64, u64> = SharedDb::new(OnDisk::open("shared").unwrap());
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        db.put(t * 100 + i, i).unwrap();
+                        assert_eq!(db.get(&(t * 100 + i)).unwrap(), Some(i));
+                    }
+                    db.delete(&(t * 100)).unwrap();
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(db.read().len(), 96);
+        assert_eq!(db.get(&324).unwrap(), Some(24));
+        assert_eq!(db.get(&300).unwrap(), None);
+    }
+
+    struct JsonCodec;
+
+    impl Codec for JsonCodec {
+        const NAME: &'static str = "json";
+
+        fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(value)?)
+        }
+
+        fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+            Ok(serde_json::from_slice(bytes)?)
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        celsius: i64,
+        tags: Vec<String>,
+        note: Option<String>,
+    }
+
+    #[test]
+    fn migrate_codec_from_bincode_to_json() {
+        remove_db("migrate_bincode");
+        remove_db("migrate_json");
+        let reading = |sensor: &str, celsius: i64, note: Option<&str>| Reading {
+            sensor: sensor.to_string(),
+            celsius,
+            tags: vec!["roof".to_string(), "say \"hi\"\n".to_string()],
+            note: note.map(str::to_string),
+        };
+        let mut db: OnDisk<String, Reading> = OnDisk::open("migrate_bincode").unwrap();
+        db.put("a".to_string(), reading("north", -4, None)).unwrap();
+        db.put_with_flags("b".to_string(), reading("south", 21, Some("sunny")), 3)
+            .unwrap();
+        db.put("gone".to_string(), reading("east", 0, None))
+            .unwrap();
+        db.delete(&"gone".to_string()).unwrap();
+
+        let migrated: OnDisk<String, Reading, JsonCodec> =
+            db.migrate_codec("migrate_json").unwrap();
+        assert_eq!(migrated.schema().codec, "json");
+        assert_eq!(migrated.keys_owned().unwrap(), vec!["a", "b"]);
+        assert_eq!(migrated.flags_of(&"b".to_string()).unwrap(), Some(3));
+        assert!(db.migrate_codec::<JsonCodec>("migrate_json").is_err());
+        drop(migrated);
+
+        let file = fs::read("migrate_json.1.db").unwrap();
+        let json =
+            br#"{"sensor":"south","celsius":21,"tags":["roof","say \"hi\"\n"],"note":"sunny"}"#;
+        assert!(file.windows(json.len()).any(|window| window == json));
+
+        let reopened: OnDisk<String, Reading, JsonCodec> = OnDisk::open("migrate_json").unwrap();
+        assert_eq!(
+            reopened.get(&"a".to_string()).unwrap(),
+            Some(reading("north", -4, None))
+        );
+        assert_eq!(
+            reopened.get(&"b".to_string()).unwrap(),
+            Some(reading("south", 21, Some("sunny")))
+        );
+        assert_eq!(reopened.get(&"gone".to_string()).unwrap(), None);
+    }
+
+    struct VarintCodec;
+
+    impl Codec for VarintCodec {
+        const NAME: &'static str = "bincode-varint";
+
+        fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+            use bincode::Options;
+            Ok(bincode::DefaultOptions::new().serialize(value)?)
+        }
+
+        fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+            use bincode::Options;
+            Ok(bincode::DefaultOptions::new().deserialize(bytes)?)
+        }
+    }
+
+    #[test]
+    fn records_use_the_chosen_codec() {
+        remove_db("codec");
+        let mut db: OnDisk<String, u64, VarintCodec> = OnDisk::open("codec").unwrap();
+        db.put("key".to_string(), 7).unwrap();
+        assert_eq!(db.value_len(&"key".to_string()).unwrap(), Some(1));
+        assert_eq!(db.schema().codec, "bincode-varint");
+        drop(db);
+
+        assert_eq!(Schema::load("codec").unwrap().codec, "bincode-varint");
+        let db: OnDisk<String, u64, VarintCodec> = OnDisk::open("codec").unwrap();
+        assert_eq!(db.get(&"key".to_string()).unwrap(), Some(7));
+
+        let reader = SingleFileReader::<String, u64, VarintCodec>::open("codec.1.db").unwrap();
+        let record = reader.record(0).unwrap();
+        assert_eq!((record.key, record.value), ("key".to_string(), Some(7)));
+        assert!(open_single_file::<String, u64>("codec.1.db")
+            .unwrap()
+            .record(0)
+            .is_err());
+    }
+
+    #[test]
+    fn compressor_applies_to_new_writes() {
+        struct Reverse;
+        impl Compressor for Reverse {
+            fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+                Ok(bytes.iter().rev().copied().collect())
+            }
+            fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+                self.compress(bytes)
+            }
+        }
+
+        remove_db("compressor");
+        let mut db: OnDisk<u64, String> = OnDisk::open("compressor").unwrap();
+        db.put(1, "plain".to_string()).unwrap();
+        db.set_compressor(Some(Box::new(Reverse)));
+        db.put(2, "squeezed".to_string()).unwrap();
+        assert_eq!(db.get(&1).unwrap(), Some("plain".to_string()));
+        assert_eq!(db.get(&2).unwrap(), Some("squeezed".to_string()));
+
+        let records: Vec<_> = open_single_file::<u64, String>("compressor.1.db")
+            .unwrap()
+            .records
+            .into_iter()
+            .map(|raw| (raw.flags & RECORD_COMPRESSED != 0, raw.value))
+            .collect();
+        let squeezed = bincode::serialize("squeezed").unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (false, bincode::serialize("plain").unwrap()),
+                (true, squeezed.into_iter().rev().collect()),
+            ]
+        );
+        let mut reader = open_single_file::<u64, String>("compressor.1.db").unwrap();
+        assert!(reader.record(1).is_err());
+        reader.set_compressor(Some(Box::new(Reverse)));
+        assert_eq!(
+            reader.record(1).unwrap().value,
+            Some("squeezed".to_string())
+        );
+
+        // prune rewrites everything compressed
+        db.prune().unwrap();
+        assert!(db
+            .key_dir
+            .values()
+            .all(|entry| entry.flags & RECORD_COMPRESSED != 0));
+        drop(db);
+
+        let mut db: OnDisk<u64, String> = OnDisk::open("compressor").unwrap();
+        db.set_compressor(Some(Box::new(Reverse)));
+        assert_eq!(db.get(&1).unwrap(), Some("plain".to_string()));
+        assert_eq!(db.get(&2).unwrap(), Some("squeezed".to_string()));
+    }
 
+    #[cfg(feature = "encryption")]
     #[test]
-    fn crash_1() {
-        let mut db: OnDisk<String, u64> = OnDisk::open("crash_1").unwrap();
+    fn cipher_encrypts_values_on_disk() {
+        struct Xor {
+            key: u8,
+            nonces: AtomicU64,
+        }
+        impl Cipher for Xor {
+            fn nonce_len(&self) -> usize {
+                8
+            }
+            fn encrypt(&self, plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+                let nonce = self.nonces.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+                Ok((nonce.to_vec(), self.decrypt(&nonce, plaintext)?))
+            }
+            fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+                let pad = self.key ^ nonce[7];
+                Ok(ciphertext.iter().map(|byte| byte ^ pad).collect())
+            }
+        }
+        let cipher = || {
+            Box::new(Xor {
+                key: 0x5a,
+                nonces: AtomicU64::new(1),
+            })
+        };
 
-        let instructions = vec![
-            Delete {
-                key: "".to_string(),
-            },
-            Delete {
-                key: "".to_string(),
-            },
-            Put {
-                key: "\0".to_string(),
-                value: 10344644575756526533u64,
-            },
-            Put {
-                key: "\u{1}".to_string(),
-                value: 72339073326448897u64,
-            },
-            Put {
-                key: "\u{1}".to_string(),
-                value: 72057594037928193u64,
-            },
-            Put {
-                key: "".to_string(),
-                value: 0,
-            },
-        ];
+        remove_db("cipher");
+        let mut db: OnDisk<u64, String> = OnDisk::open("cipher").unwrap();
+        db.put(1, "plain".to_string()).unwrap();
+        db.set_cipher(Some(cipher()));
+        db.put(2, "secret".to_string()).unwrap();
+        assert_eq!(db.get(&1).unwrap(), Some("plain".to_string()));
+        assert_eq!(db.get(&2).unwrap(), Some("secret".to_string()));
 
-        for instruction in instructions {
-            eval_op(&mut db, instruction);
+        let mut reader = open_single_file::<u64, String>("cipher.1.db").unwrap();
+        let raw = &reader.records[1];
+        assert_eq!(raw.flags & RECORD_ENCRYPTED, RECORD_ENCRYPTED);
+        assert_eq!(raw.value[..8], 1u64.to_be_bytes());
+        assert!(!raw.value.windows(6).any(|window| window == b"secret"));
+        assert!(reader.record(1).is_err());
+        reader.set_cipher(Some(cipher()));
+        assert_eq!(reader.record(1).unwrap().value, Some("secret".to_string()));
+
+        // prune encrypts the values written before the cipher was set
+        db.prune().unwrap();
+        assert!(db
+            .key_dir
+            .values()
+            .all(|entry| entry.flags & RECORD_ENCRYPTED != 0));
+        drop(db);
+
+        let mut db: OnDisk<u64, String> = OnDisk::open("cipher").unwrap();
+        assert!(db.get(&2).is_err());
+        db.set_cipher(Some(cipher()));
+        assert_eq!(db.get(&1).unwrap(), Some("plain".to_string()));
+        assert_eq!(db.get(&2).unwrap(), Some("secret".to_string()));
+        assert!(validate("cipher").unwrap().is_ok());
+    }
+
+    #[test]
+    fn get_many_reads_keys_in_order() {
+        remove_db("get_many");
+        let mut db: OnDisk<u64, String> = OnDisk::open("get_many").unwrap();
+        for i in 0..3 {
+            db.put(i, format!("first {}", i)).unwrap();
+        }
+        db.sync().unwrap();
+        for i in 3..6 {
+            db.put(i, format!("second {}", i)).unwrap();
         }
+        db.delete(&4).unwrap();
 
-        assert!(db.sync().is_ok());
+        let values = db.get_many(&[5, 0, 9, 4, 2, 3]).unwrap();
+        assert_eq!(
+            values,
+            vec![
+                Some("second 5".to_string()),
+                Some("first 0".to_string()),
+                None,
+                None,
+                Some("first 2".to_string()),
+                Some("second 3".to_string()),
+            ]
+        );
+        assert!(db.get_many(&[]).unwrap().is_empty());
     }
 
     #[test]
-    fn crash_2() {
-        let mut db: OnDisk<String, u64> = OnDisk::open("crash_2").unwrap();
+    fn stats_count_keys_and_dead_space() {
+        remove_db("stats");
+        let mut db: OnDisk<u64, String> = OnDisk::open("stats").unwrap();
+        assert_eq!(
+            db.stats().unwrap(),
+            DbStats {
+                files: 1,
+                ..DbStats::default()
+            }
+        );
 
-        let instructions = vec![
-            Put {
-                key: "\0".to_string(),
-                value: 16969173279757565696,
-            },
-            Sync,
-            Prune,
-        ];
+        for i in 0..4 {
+            db.put(i, "value".to_string()).unwrap();
+        }
+        db.sync().unwrap();
+        db.delete(&0).unwrap();
+        db.put(1, "value".to_string()).unwrap();
 
-        for instruction in instructions {
-            eval_op(&mut db, instruction);
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.live_keys, 3);
+        assert_eq!(stats.tombstones, 1);
+        assert_eq!(stats.files, 2);
+        assert_eq!(
+            stats.disk_bytes,
+            fs::metadata("stats.1.db").unwrap().len() + fs::metadata("stats.2.db").unwrap().len()
+        );
+        assert!(stats.reclaimable_bytes > 0);
+        assert!(stats.dead_space_ratio() > 0.0 && stats.dead_space_ratio() < 1.0);
+
+        db.prune().unwrap();
+        assert_eq!(db.stats().unwrap().reclaimable_bytes, 0);
+    }
+
+    #[test]
+    fn auto_compaction_prunes_past_the_threshold() {
+        remove_db("auto_compact");
+        let mut db: OnDisk<u64, String> =
+            OnDisk::open_with_auto_compact("auto_compact", Some(0.5)).unwrap();
+        for i in 0..4 {
+            db.put(i, "value".to_string()).unwrap();
         }
+        db.sync().unwrap();
 
-        assert!(db.sync().is_ok());
+        // one dead record against three live ones stays under the threshold
+        db.delete(&0).unwrap();
+        assert!(db.stats().unwrap().reclaimable_bytes > 0);
+        assert_eq!(data_file_ids("auto_compact").unwrap(), vec![1, 2]);
+
+        db.delete(&1).unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.reclaimable_bytes, 0);
+        assert_eq!(stats.tombstones, 0);
+        assert_eq!(db.get(&2).unwrap(), Some("value".to_string()));
+        assert_eq!(db.get(&0).unwrap(), None);
+
+        db.set_auto_compact_threshold(None);
+        db.delete(&2).unwrap();
+        db.delete(&3).unwrap();
+        assert!(db.stats().unwrap().reclaimable_bytes > 0);
+        drop(db);
+
+        // a threshold of 1.0 turns it off, however much space is dead
+        let mut db: OnDisk<u64, String> =
+            OnDisk::open_with_auto_compact("auto_compact", Some(1.0)).unwrap();
+        assert_eq!(db.auto_compact_threshold, None);
+        db.put(4, "value".to_string()).unwrap();
+        for _ in 0..3 {
+            db.put(5, "value".to_string()).unwrap();
+        }
+        db.delete(&5).unwrap();
+        let stats = db.stats().unwrap();
+        assert!(stats.reclaimable_bytes > db.live_bytes);
+        assert!(stats.tombstones > 0);
+    }
+
+    #[test]
+    fn open_in_keeps_files_in_the_directory() {
+        let _ = fs::remove_dir_all("open_in_dir");
+        let mut db: OnDisk<u64, String> = OnDisk::open_in("open_in_dir/nested", "db").unwrap();
+        db.put(1, "one".to_string()).unwrap();
+        db.sync().unwrap();
+        db.put(2, "two".to_string()).unwrap();
+        db.prune().unwrap();
+        drop(db);
+
+        assert!(fs::exists("open_in_dir/nested/db.1.db").unwrap());
+        assert!(fs::exists("open_in_dir/nested/db.schema").unwrap());
+        assert!(!fs::exists("db.1.db").unwrap());
+
+        let db: OnDisk<u64, String> = OnDisk::open_in("open_in_dir/nested", "db").unwrap();
+        assert_eq!(db.get(&1).unwrap(), Some("one".to_string()));
+        assert_eq!(db.get(&2).unwrap(), Some("two".to_string()));
+        drop(db);
+        fs::remove_dir_all("open_in_dir").unwrap();
+    }
+
+    #[test]
+    fn put_returns_the_previous_value() {
+        remove_db("put_previous");
+        let mut db: OnDisk<u64, String> = OnDisk::open("put_previous").unwrap();
+        assert_eq!(db.put(1, "one".to_string()).unwrap(), None);
+        assert_eq!(
+            db.put(1, "uno".to_string()).unwrap(),
+            Some("one".to_string())
+        );
+        db.delete(&1).unwrap();
+        assert_eq!(db.put(1, "eins".to_string()).unwrap(), None);
+        assert_eq!(db.get(&1).unwrap(), Some("eins".to_string()));
+    }
+
+    #[test]
+    fn compare_and_swap_only_writes_on_a_match() {
+        remove_db("compare_and_swap");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("compare_and_swap").unwrap();
+        assert!(!db.compare_and_swap(&1, Some(&0), 1).unwrap());
+        assert_eq!(db.get(&1).unwrap(), None);
+        assert!(db.compare_and_swap(&1, None, 1).unwrap());
+        assert!(!db.compare_and_swap(&1, None, 5).unwrap());
+        assert!(!db.compare_and_swap(&1, Some(&0), 5).unwrap());
+        assert!(db.compare_and_swap(&1, Some(&1), 2).unwrap());
+        assert_eq!(db.get(&1).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn get_or_insert_with_only_calls_f_when_missing() {
+        remove_db("get_or_insert");
+        let mut db: OnDisk<u64, String> = OnDisk::open("get_or_insert").unwrap();
+        let value = db.get_or_insert_with(1, || "made".to_string()).unwrap();
+        assert_eq!(value, "made");
+        assert_eq!(db.get(&1).unwrap(), Some("made".to_string()));
+
+        let value = db
+            .get_or_insert_with(1, || panic!("key 1 is there"))
+            .unwrap();
+        assert_eq!(value, "made");
+    }
+
+    #[test]
+    fn open_truncates_a_torn_last_record() {
+        remove_db("torn_tail");
+        let mut db: OnDisk<u64, String> = OnDisk::open("torn_tail").unwrap();
+        for i in 0..3 {
+            db.put(i, format!("value {}", i)).unwrap();
+        }
+        drop(db);
+        let whole = fs::read("torn_tail.1.db").unwrap();
+        let last_start = open_single_file::<u64, String>("torn_tail.1.db")
+            .unwrap()
+            .records[2]
+            .start as usize;
+
+        // cut off inside the header, then inside the value
+        for cut in [last_start + 10, whole.len() - 3] {
+            for f_id in 2..4 {
+                let _ = fs::remove_file(format!("torn_tail.{}.db", f_id));
+            }
+            fs::write("torn_tail.1.db", &whole[..cut]).unwrap();
+            let mut db: OnDisk<u64, String> = OnDisk::open("torn_tail").unwrap();
+            assert_eq!(db.len(), 2);
+            assert_eq!(db.get(&2).unwrap(), None);
+            assert_eq!(
+                fs::metadata("torn_tail.1.db").unwrap().len(),
+                last_start as u64
+            );
+
+            db.put(3, "after".to_string()).unwrap();
+            drop(db);
+            let db: OnDisk<u64, String> = OnDisk::open("torn_tail").unwrap();
+            assert_eq!(db.get(&1).unwrap(), Some("value 1".to_string()));
+            assert_eq!(db.get(&3).unwrap(), Some("after".to_string()));
+        }
+
+        // damage with intact records after it is not a torn append
+        let mut damaged = whole.clone();
+        damaged[last_start - 1] ^= 0xff;
+        fs::write("torn_tail.1.db", &damaged).unwrap();
+        assert!(OnDisk::<u64, String>::open("torn_tail").is_err());
+    }
+
+    #[test]
+    fn has_unsynced_writes_until_flushed() {
+        remove_db("unsynced_writes");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("unsynced_writes").unwrap();
+        assert!(!db.has_unsynced_writes());
+        db.put(1, 1).unwrap();
+        assert!(db.has_unsynced_writes());
+        db.flush().unwrap();
+        assert!(!db.has_unsynced_writes());
+        db.delete(&1).unwrap();
+        assert!(db.has_unsynced_writes());
+        db.sync().unwrap();
+        assert!(!db.has_unsynced_writes());
+    }
+
+    #[test]
+    fn close_syncs_and_marks_a_clean_shutdown() {
+        remove_db("close");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("close").unwrap();
+        db.put(1, 1).unwrap();
+        db.close().unwrap();
+        assert!(was_clean_shutdown("close").unwrap());
+        assert_eq!(data_file_ids("close").unwrap(), vec![1, 2]);
+
+        let db: OnDisk<u64, u64> = OnDisk::open("close").unwrap();
+        assert!(!was_clean_shutdown("close").unwrap());
+        assert_eq!(db.get(&1).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn verify_reports_keys_with_bad_records() {
+        remove_db("verify");
+        let mut db: OnDisk<u64, String> = OnDisk::open("verify").unwrap();
+        for i in 0..4 {
+            db.put(i, format!("value {}", i)).unwrap();
+        }
+        db.sync().unwrap();
+        assert!(db.verify().unwrap().is_empty());
+
+        // flip the last byte of key 2's value and cut key 3's record short
+        let mut bytes = fs::read("verify.1.db").unwrap();
+        let end = db.key_dir[&2].slot.end as usize;
+        bytes[end - 1] ^= 0xff;
+        bytes.truncate(db.key_dir[&3].slot.end as usize - 1);
+        fs::write("verify.1.db", bytes).unwrap();
+        assert_eq!(db.verify().unwrap(), vec![2, 3]);
+        assert_eq!(db.get(&1).unwrap(), Some("value 1".to_string()));
+    }
+
+    #[test]
+    fn open_settles_an_interrupted_prune() {
+        remove_db("prune_swap");
+        remove_db("prune_swap_new");
+
+        let mut db: OnDisk<u64, String> = OnDisk::open("prune_swap").unwrap();
+        for i in 0..3 {
+            db.put(i, "old".to_string()).unwrap();
+            db.sync().unwrap();
+        }
+        drop(db);
+        let mut db: OnDisk<u64, String> = OnDisk::open("prune_swap_new").unwrap();
+        db.put(7, "new".to_string()).unwrap();
+        db.prune().unwrap();
+        drop(db);
+
+        // without a marker, what prune left behind is thrown away
+        fs::copy("prune_swap_new.1.db", "prune_swap.1.compacted.db").unwrap();
+        fs::write("prune_swap.2.temp.db", b"partial").unwrap();
+        let db: OnDisk<u64, String> = OnDisk::open("prune_swap").unwrap();
+        assert_eq!(db.len(), 3);
+        drop(db);
+        assert!(!fs::exists("prune_swap.1.compacted.db").unwrap());
+        assert!(!fs::exists("prune_swap.2.temp.db").unwrap());
+
+        // with one, a swap cut short after its first rename is finished
+        let old_files = data_file_ids("prune_swap").unwrap();
+        assert!(old_files.len() > 1);
+        fs::copy("prune_swap_new.1.db", "prune_swap.1.db").unwrap();
+        fs::write(prune_marker_path("prune_swap"), "1").unwrap();
+        let db: OnDisk<u64, String> = OnDisk::open("prune_swap").unwrap();
+        assert_eq!(db.keys_owned().unwrap(), vec![7]);
+        assert_eq!(db.get(&7).unwrap(), Some("new".to_string()));
+        assert!(!fs::exists(prune_marker_path("prune_swap")).unwrap());
+    }
+
+    #[test]
+    fn read_only_open_refuses_writes() {
+        remove_db("read_only");
+        assert!(OnDisk::<u64, String>::open_read_only("read_only").is_err());
+
+        let mut writer: OnDisk<u64, String> = OnDisk::open("read_only").unwrap();
+        writer.put(1, "one".to_string()).unwrap();
+        writer.put(2, "two".to_string()).unwrap();
+        writer.delete(&2).unwrap();
+        writer.flush().unwrap();
+        let files = data_file_ids("read_only").unwrap();
+        let len = fs::metadata("read_only.1.db").unwrap().len();
+
+        let mut reader: OnDisk<u64, String> = OnDisk::open_read_only("read_only").unwrap();
+        assert_eq!(reader.get(&1).unwrap(), Some("one".to_string()));
+        assert_eq!(reader.get(&2).unwrap(), None);
+        assert!(reader.put(3, "three".to_string()).is_err());
+        assert!(reader.delete(&1).is_err());
+        assert!(reader.sync().is_err());
+        assert!(reader.prune().is_err());
+        assert!(reader.clear().is_err());
+        assert_eq!(reader.len(), 1);
+        drop(reader);
+
+        assert_eq!(data_file_ids("read_only").unwrap(), files);
+        assert_eq!(fs::metadata("read_only.1.db").unwrap().len(), len);
+        writer.put(3, "three".to_string()).unwrap();
+        assert_eq!(writer.get(&1).unwrap(), Some("one".to_string()));
+    }
+
+    #[test]
+    fn scan_prefix_returns_matching_keys() {
+        remove_db("scan_prefix");
+        let mut db: OnDisk<String, u64> = OnDisk::open("scan_prefix").unwrap();
+        for (i, key) in ["user", "user:1", "user:2", "users", "item:1", "usf"]
+            .iter()
+            .enumerate()
+        {
+            db.put(key.to_string(), i as u64).unwrap();
+        }
+        db.delete(&"user:2".to_string()).unwrap();
+
+        assert_eq!(
+            db.scan_prefix(&"user".to_string()).unwrap(),
+            vec![
+                ("user".to_string(), 0),
+                ("user:1".to_string(), 1),
+                ("users".to_string(), 3),
+            ]
+        );
+        assert_eq!(db.scan_prefix(&"".to_string()).unwrap().len(), 5);
+        assert!(db.scan_prefix(&"zz".to_string()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn merge_from_keeps_the_latest_write() {
+        remove_db("merge_into");
+        remove_db("merge_from");
+        let mut ours: OnDisk<u64, String> = OnDisk::open("merge_into").unwrap();
+        let mut theirs: OnDisk<u64, String> = OnDisk::open("merge_from").unwrap();
+        ours.put(1, "ours, older".to_string()).unwrap();
+        theirs.put(2, "only theirs".to_string()).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        theirs.put(1, "theirs, newer".to_string()).unwrap();
+        theirs.put_with_flags(3, "flagged".to_string(), 7).unwrap();
+        theirs.put(4, "deleted".to_string()).unwrap();
+        theirs.delete(&4).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        theirs.put(5, "theirs, older".to_string()).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        ours.put(5, "ours, newer".to_string()).unwrap();
+
+        ours.merge_from(&theirs).unwrap();
+        assert_eq!(
+            ours.items().unwrap(),
+            vec![
+                (&1, "theirs, newer".to_string()),
+                (&2, "only theirs".to_string()),
+                (&3, "flagged".to_string()),
+                (&5, "ours, newer".to_string()),
+            ]
+        );
+        assert_eq!(ours.flags_of(&3).unwrap(), Some(7));
+        assert_eq!(theirs.len(), 4);
+    }
+
+    #[test]
+    fn backup_copies_live_records_to_a_new_prefix() {
+        remove_db("backup_source");
+        remove_db("backup_copy");
+        let mut db: OnDisk<u64, String> = OnDisk::open("backup_source").unwrap();
+        for i in 0..10 {
+            db.put(i, format!("first {}", i)).unwrap();
+        }
+        for i in 0..5 {
+            db.put(i, format!("second {}", i)).unwrap();
+        }
+        db.delete(&9).unwrap();
+        let source_files = data_file_ids("backup_source").unwrap();
+        let source_len = fs::metadata("backup_source.1.db").unwrap().len();
+
+        db.backup("backup_copy").unwrap();
+        assert_eq!(data_file_ids("backup_source").unwrap(), source_files);
+        assert_eq!(
+            fs::metadata("backup_source.1.db").unwrap().len(),
+            source_len
+        );
+        assert!(fs::metadata("backup_copy.1.db").unwrap().len() < source_len);
+        assert!(db.backup("backup_copy").is_err());
+
+        let mut copy: OnDisk<u64, String> = OnDisk::open("backup_copy").unwrap();
+        assert_eq!(copy.items().unwrap(), db.items().unwrap());
+    }
+
+    #[test]
+    fn get_reader_streams_the_value_bytes() {
+        remove_db("get_reader");
+        let mut db: OnDisk<u64, Vec<u8>> = OnDisk::open("get_reader").unwrap();
+        let blob: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        db.put(1, blob.clone()).unwrap();
+
+        let mut streamed = vec![];
+        db.get_reader(&1)
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut streamed)
+            .unwrap();
+        assert_eq!(streamed, bincode::serialize(&blob).unwrap());
+        assert!(db.get_reader(&2).unwrap().is_none());
+    }
+
+    #[test]
+    fn records_note_their_checksum() {
+        remove_db("checksum_choice");
+        let mut db: OnDisk<u64, String> = OnDisk::open("checksum_choice").unwrap();
+        db.put(1, "cksum".to_string()).unwrap();
+        db.set_checksum(Checksum::Iscsi);
+        db.put(2, "iscsi".to_string()).unwrap();
+        db.set_checksum(Checksum::IsoHdlc);
+        db.put(3, "iso-hdlc".to_string()).unwrap();
+        drop(db);
+
+        let mut noted = vec![];
+        scan_file("checksum_choice.1.db", |record| {
+            noted.push(Checksum::of_flags(record.flags).unwrap())
+        })
+        .unwrap();
+        assert_eq!(noted, [Checksum::Cksum, Checksum::Iscsi, Checksum::IsoHdlc]);
+        assert!(validate("checksum_choice").unwrap().is_ok());
+        let mut db: OnDisk<u64, String> = OnDisk::open("checksum_choice").unwrap();
+        assert_eq!(db.get(&2).unwrap(), Some("iscsi".to_string()));
+        assert_eq!(db.get(&3).unwrap(), Some("iso-hdlc".to_string()));
+        assert!(db.verify().unwrap().is_empty());
+        db.prune().unwrap();
+        assert_eq!(db.get(&1).unwrap(), Some("cksum".to_string()));
+    }
+
+    #[test]
+    fn deleted_keys_lists_tombstoned_keys_until_prune() {
+        remove_db("deleted_keys");
+        let mut db: OnDisk<u64, String> = OnDisk::open("deleted_keys").unwrap();
+        for i in 0..4 {
+            db.put(i, i.to_string()).unwrap();
+        }
+        db.put(0, "again".to_string()).unwrap();
+        db.delete(&1).unwrap();
+        db.delete(&2).unwrap();
+        db.put(2, "back".to_string()).unwrap();
+        assert_eq!(db.deleted_keys().unwrap(), [&1]);
+
+        db.prune().unwrap();
+        assert!(db.deleted_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn for_loops_iterate_a_database_reference() {
+        remove_db("into_iterator");
+        let mut db: OnDisk<u64, String> = OnDisk::open("into_iterator").unwrap();
+        for i in [3, 1, 2] {
+            db.put(i, i.to_string()).unwrap();
+        }
+        let mut keys = vec![];
+        for (key, value) in &db {
+            assert_eq!(value, key.to_string());
+            keys.push(key);
+        }
+        assert_eq!(keys, [1, 2, 3]);
+        assert!(db.take_iter_error().is_none());
+        let odd: Vec<_> = (&db).into_iter().filter(|(key, _)| key % 2 == 1).collect();
+        assert_eq!(odd.len(), 2);
+
+        // an error ends the loop, and is there to check afterwards
+        db.set_key_dir_limit(Some(2)).unwrap();
+        for i in 4..20 {
+            db.put(i, i.to_string()).unwrap();
+        }
+        db.sync().unwrap();
+        db.put(20, "20".to_string()).unwrap();
+        fs::remove_file(db.spill_path()).unwrap();
+        assert_eq!((&db).into_iter().count(), 0);
+        assert!(db.take_iter_error().is_some());
+        assert!(db.take_iter_error().is_none());
+    }
+
+    #[test]
+    fn from_iter_at_seeds_a_new_database() {
+        remove_db("from_iter_at");
+        let pairs: Vec<(u64, String)> = (0..20).map(|i| (i, i.to_string())).collect();
+        let db: OnDisk<u64, String> = OnDisk::from_iter_at("from_iter_at", pairs.clone()).unwrap();
+        let items: Vec<_> = db.iter().map(Result::unwrap).collect();
+        assert_eq!(items, pairs);
+        drop(db);
+        assert!(OnDisk::<u64, String>::from_iter_at("from_iter_at", pairs).is_err());
+    }
+
+    #[test]
+    fn overwrites_leave_the_replaced_record_in_place() {
+        remove_db("overwrite_slots");
+        let mut db: OnDisk<u64, String> = OnDisk::open("overwrite_slots").unwrap();
+        db.put(0, "x".repeat(500)).unwrap();
+        db.put(1, "keep".to_string()).unwrap();
+        let (_, old_start, old_end) = db.byte_range(&0).unwrap().unwrap();
+
+        db.put(0, "smaller".to_string()).unwrap();
+        let (_, start, _) = db.byte_range(&0).unwrap().unwrap();
+        assert!(start >= old_end);
+        assert_eq!(db.free_slots.iter().count(), 1);
+
+        db.put(2, "new".to_string()).unwrap();
+        let (_, start, end) = db.byte_range(&2).unwrap().unwrap();
+        assert_eq!(start, old_start);
+        assert!(end < old_end);
+        assert_eq!(db.free_slots.iter().count(), 1);
+
+        drop(db);
+        assert!(validate("overwrite_slots").unwrap().is_ok());
+        let db: OnDisk<u64, String> = OnDisk::open("overwrite_slots").unwrap();
+        assert_eq!(db.get(&0).unwrap(), Some("smaller".to_string()));
+        assert_eq!(db.get(&1).unwrap(), Some("keep".to_string()));
+        assert_eq!(db.get(&2).unwrap(), Some("new".to_string()));
+    }
+
+    #[test]
+    fn deduplicated_keys_survive_losing_the_hint() {
+        remove_db("dedup_alias");
+        let mut db: OnDisk<String, String> = OnDisk::open("dedup_alias").unwrap();
+        db.set_dedup_on_prune(true);
+        db.put("a".to_string(), "same".to_string()).unwrap();
+        db.put("b".to_string(), "same".to_string()).unwrap();
+        db.put("c".to_string(), "x".repeat(100)).unwrap();
+        db.prune().unwrap();
+        db.delete(&"c".to_string()).unwrap();
+        db.put("d".to_string(), "y".repeat(100)).unwrap();
+        assert_eq!(db.byte_range(&"d".to_string()).unwrap().unwrap().0, 1);
+        assert!(!fs::exists("dedup_alias.1.hint.db").unwrap());
+        drop(db);
+
+        let reader: SingleFileReader<String, String> =
+            open_single_file("dedup_alias.1.db").unwrap();
+        let aliases: Vec<_> = reader
+            .iter()
+            .map(Result::unwrap)
+            .filter(|record| record.alias_of.is_some())
+            .map(|record| record.key)
+            .collect();
+        assert_eq!(aliases, ["b"]);
+
+        let db: OnDisk<String, String> = OnDisk::open("dedup_alias").unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some("same".to_string()));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some("same".to_string()));
+        assert_eq!(db.get(&"c".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"d".to_string()).unwrap(), Some("y".repeat(100)));
+        assert_eq!(
+            db.byte_range(&"a".to_string()).unwrap(),
+            db.byte_range(&"b".to_string()).unwrap()
+        );
+    }
+
+    #[test]
+    fn prune_reclaims_space_after_sync_and_reopen() {
+        remove_db("prune_after_sync");
+        let disk_bytes = || {
+            data_file_ids("prune_after_sync")
+                .unwrap()
+                .into_iter()
+                .map(|f_id| {
+                    fs::metadata(format!("prune_after_sync.{}.db", f_id))
+                        .unwrap()
+                        .len()
+                })
+                .sum::<u64>()
+        };
+        let mut db: OnDisk<u64, String> = OnDisk::open("prune_after_sync").unwrap();
+        for i in 0..50 {
+            db.put(i, "first".repeat(10)).unwrap();
+        }
+        for i in 0..50 {
+            db.put(i, "second".repeat(10)).unwrap();
+        }
+        db.sync().unwrap();
+        let before = disk_bytes();
+        db.prune().unwrap();
+        assert!(disk_bytes() < before);
+        assert_eq!(db.get(&7).unwrap(), Some("second".repeat(10)));
+
+        for i in 0..50 {
+            db.delete(&i).unwrap();
+        }
+        db.close().unwrap();
+        let mut db: OnDisk<u64, String> = OnDisk::open("prune_after_sync").unwrap();
+        db.prune().unwrap();
+        assert_eq!(disk_bytes(), 0);
+        assert!(db.is_empty());
+    }
+
+    #[test]
+    fn buffered_writes_are_read_everywhere() {
+        remove_db("coalesce_reads");
+        let mut db: OnDisk<String, Vec<u64>> = OnDisk::open("coalesce_reads").unwrap();
+        db.set_write_coalescing(Some(16)).unwrap();
+        for i in 0..4 {
+            db.append_to_list("list".to_string(), i).unwrap();
+        }
+        let key = "list".to_string();
+        assert_eq!(db.get(&key).unwrap(), Some(vec![0, 1, 2, 3]));
+
+        assert!(db.cache_value(&key).unwrap());
+        assert_eq!(*db.get_cow(&key).unwrap().unwrap(), vec![0, 1, 2, 3]);
+
+        let snapshot = db.snapshot().unwrap();
+        let items: Vec<_> = db.iter_snapshot(&snapshot).map(Result::unwrap).collect();
+        assert_eq!(items, [(key.clone(), vec![0, 1, 2, 3])]);
+        let mut exported = vec![];
+        db.export_from_snapshot(&snapshot, &mut exported).unwrap();
+        let exported: (String, Vec<u64>) = bincode::deserialize(&exported).unwrap();
+        assert_eq!(exported, (key, vec![0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn put_replaces_an_unreadable_value() {
+        remove_db("put_corrupt");
+        let mut db: OnDisk<String, String> = OnDisk::open("put_corrupt").unwrap();
+        let key = "a".to_string();
+        db.put(key.clone(), "old".to_string()).unwrap();
+        let (_, _, end) = db.byte_range(&key).unwrap().unwrap();
+        let mut file = db.curr_file().unwrap();
+        file.seek(SeekFrom::Start(end - 1)).unwrap();
+        file.write_all(b"!").unwrap();
+        assert!(db.get(&key).is_err());
+
+        assert_eq!(db.put(key.clone(), "new".to_string()).unwrap(), None);
+        assert_eq!(db.get(&key).unwrap(), Some("new".to_string()));
+
+        // the old value comes back as stored, past the read middleware
+        db.set_value_middleware(|value| value, |value| format!("read {}", value));
+        assert_eq!(
+            db.put(key.clone(), "newer".to_string()).unwrap(),
+            Some("new".to_string())
+        );
+
+        // but failing to find the old record is still an error
+        db.set_key_dir_limit(Some(2)).unwrap();
+        for i in 0..10 {
+            db.put(i.to_string(), i.to_string()).unwrap();
+        }
+        db.sync().unwrap();
+        db.put("x".to_string(), "x".to_string()).unwrap();
+        fs::remove_file(db.spill_path()).unwrap();
+        assert!(db.put("0".to_string(), "again".to_string()).is_err());
+    }
+
+    #[test]
+    fn losing_the_spill_file_is_an_error() {
+        remove_db("spill_lost");
+        let mut db: OnDisk<u64, String> = OnDisk::open("spill_lost").unwrap();
+        db.set_key_dir_limit(Some(16)).unwrap();
+        for i in 0..100 {
+            db.put(i, format!("value {}", i)).unwrap();
+            if i % 10 == 9 {
+                db.sync().unwrap();
+            }
+        }
+        fs::remove_file(db.spill_path()).unwrap();
+
+        assert!(db.keys_owned().is_err());
+        assert!(db.contains_key(&0).is_err());
+        assert!(db.byte_range(&0).is_err());
+        assert!(db.live_value_bytes().is_err());
+        assert!(db.keys().is_err());
+    }
+
+    #[test]
+    fn failed_commit_leaves_nothing_behind() {
+        remove_db("txn_fail");
+        let mut db: OnDisk<String, Fallible> = OnDisk::open("txn_fail").unwrap();
+        db.put("a".to_string(), Fallible(1)).unwrap();
+        db.put("b".to_string(), Fallible(2)).unwrap();
+
+        let mut txn = db.begin();
+        txn.put("a".to_string(), Fallible(10));
+        txn.delete(&"b".to_string());
+        txn.put("c".to_string(), Fallible(3));
+        txn.put("d".to_string(), Fallible(u64::MAX));
+        assert!(txn.commit().is_err());
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(Fallible(1)));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some(Fallible(2)));
+        assert_eq!(db.get(&"c".to_string()).unwrap(), None);
+        db.sync().unwrap();
+        drop(db);
+
+        let db: OnDisk<String, Fallible> = OnDisk::open("txn_fail").unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(Fallible(1)));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some(Fallible(2)));
+        assert_eq!(db.get(&"c".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn transaction_without_its_commit_record_is_dropped() {
+        remove_db("txn_torn");
+        let mut db: OnDisk<String, u64> = OnDisk::open("txn_torn").unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        db.put("b".to_string(), 2).unwrap();
+
+        let mut txn = db.begin();
+        txn.put("a".to_string(), 10);
+        txn.delete(&"b".to_string());
+        txn.put("c".to_string(), 3);
+        txn.commit().unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(10));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"c".to_string()).unwrap(), Some(3));
+        drop(db);
+
+        // as if the crash came right before the commit record went out
+        let path = "txn_torn.1.db";
+        let len = fs::metadata(path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(len - HEADER_LEN - 8).unwrap();
+        drop(file);
+
+        let mut db: OnDisk<String, u64> = OnDisk::open("txn_torn").unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(1));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some(2));
+        assert_eq!(db.get(&"c".to_string()).unwrap(), None);
+        db.put("c".to_string(), 4).unwrap();
+        db.prune().unwrap();
+        drop(db);
+        let db: OnDisk<String, u64> = OnDisk::open("txn_torn").unwrap();
+        assert_eq!(db.get(&"a".to_string()).unwrap(), Some(1));
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some(2));
+        assert_eq!(db.get(&"c".to_string()).unwrap(), Some(4));
+    }
+
+    #[test]
+    fn every_key_method_normalizes() {
+        remove_db("normalize_all");
+        let mut db: OnDisk<String, Vec<u64>> = OnDisk::open("normalize_all").unwrap();
+        db.set_key_normalizer(|key: &String| key.to_lowercase());
+        let upper = "KEY".to_string();
+        let lower = "key".to_string();
+        db.append_to_list(upper.clone(), 1).unwrap();
+        db.append_to_list(lower.clone(), 2).unwrap();
+        db.put_with_flags("OTHER".to_string(), vec![], 5).unwrap();
+
+        assert_eq!(db.get(&upper).unwrap(), Some(vec![1, 2]));
+        assert_eq!(db.get_as::<Vec<u64>>(&upper).unwrap(), Some(vec![1, 2]));
+        assert_eq!(db.flags_of(&"Other".to_string()).unwrap(), Some(5));
+        assert_eq!(db.timestamp(&upper).unwrap(), db.timestamp(&lower).unwrap());
+        assert!(db.timestamp(&upper).unwrap().is_some());
+        assert_eq!(db.value_len(&upper).unwrap(), db.value_len(&lower).unwrap());
+        assert_eq!(
+            db.byte_range(&upper).unwrap(),
+            db.byte_range(&lower).unwrap()
+        );
+        assert!(db.cache_value(&upper).unwrap());
+        assert_eq!(*db.get_cow(&lower).unwrap().unwrap(), vec![1, 2]);
+        assert_eq!(*db.get_cow(&upper).unwrap().unwrap(), vec![1, 2]);
+        assert_eq!(db.next_key(&"K".to_string()).unwrap(), Some(lower.clone()));
+        assert_eq!(db.prev_key(&"L".to_string()).unwrap(), Some(lower.clone()));
+        let mut cursor = db.cursor().unwrap();
+        assert!(cursor.seek(&"L".to_string()));
+        assert_eq!(cursor.key(), Some(&"other".to_string()));
+
+        db.soft_delete(&upper).unwrap();
+        assert_eq!(db.get(&lower).unwrap(), None);
+        assert!(db.restore(&upper).unwrap());
+        assert_eq!(db.get(&lower).unwrap(), Some(vec![1, 2]));
+
+        let mut txn = db.begin();
+        txn.put(upper.clone(), vec![3]);
+        assert_eq!(txn.get(&lower).unwrap(), Some(vec![3]));
+        txn.delete(&"Other".to_string());
+        assert_eq!(txn.get(&"other".to_string()).unwrap(), None);
+        txn.commit().unwrap();
+        assert_eq!(db.get(&lower).unwrap(), Some(vec![3]));
+        assert_eq!(db.keys_owned().unwrap(), vec![lower]);
+    }
+
+    #[test]
+    fn shared_compaction_lets_writers_in() {
+        use std::sync::atomic::AtomicBool;
+
+        static ARMED: AtomicBool = AtomicBool::new(false);
+        static COPYING: AtomicBool = AtomicBool::new(false);
+        static WRITER_READY: AtomicBool = AtomicBool::new(false);
+        static WRITTEN: AtomicBool = AtomicBool::new(false);
+        static WRITTEN_DURING_COPY: AtomicBool = AtomicBool::new(false);
+        const LAST_VALUE: u64 = u64::MAX - 1;
+
+        // slows the copy down until the writer's puts are in, so it queues up
+        // on the lock during the first step and has every gap between steps
+        // to get in, without a step waiting on it while holding the lock
+        struct SlowCodec;
+
+        impl Codec for SlowCodec {
+            const NAME: &'static str = "bincode";
+
+            fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+                BincodeCodec::serialize(value)
+            }
+
+            fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+                if !ARMED.load(Ordering::SeqCst) {
+                    return BincodeCodec::deserialize(bytes);
+                }
+                if !COPYING.swap(true, Ordering::SeqCst) {
+                    let deadline = SystemTime::now() + Duration::from_secs(5);
+                    while !WRITER_READY.load(Ordering::SeqCst) && SystemTime::now() < deadline {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                if !WRITTEN.load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                if bytes == LAST_VALUE.to_le_bytes() {
+                    WRITTEN_DURING_COPY.store(WRITTEN.load(Ordering::SeqCst), Ordering::SeqCst);
+                }
+                BincodeCodec::deserialize(bytes)
+            }
+        }
+
+        remove_db("shared_compact");
+        let mut db: OnDisk<String, u64, SlowCodec> = OnDisk::open("shared_compact").unwrap();
+        for i in 0..200 {
+            db.put(format!("{:03}", i), i).unwrap();
+        }
+        db.put("199".to_string(), LAST_VALUE).unwrap();
+        db.delete(&"000".to_string()).unwrap();
+        db.sync().unwrap();
+        let db = SharedDb::new(db);
+        ARMED.store(true, Ordering::SeqCst);
+
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                while !COPYING.load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                WRITER_READY.store(true, Ordering::SeqCst);
+                let mut db = db.write();
+                db.put("new".to_string(), 1).unwrap();
+                db.put("001".to_string(), 100).unwrap();
+                WRITTEN.store(true, Ordering::SeqCst);
+            })
+        };
+        db.compact().unwrap();
+        writer.join().unwrap();
+        assert!(WRITTEN_DURING_COPY.load(Ordering::SeqCst));
+
+        assert_eq!(db.get(&"000".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"001".to_string()).unwrap(), Some(100));
+        assert_eq!(db.get(&"002".to_string()).unwrap(), Some(2));
+        assert_eq!(db.get(&"199".to_string()).unwrap(), Some(LAST_VALUE));
+        assert_eq!(db.get(&"new".to_string()).unwrap(), Some(1));
+        assert_eq!(db.read().len(), 200);
+    }
+
+    #[test]
+    fn open_finishes_an_interrupted_replace_all() {
+        remove_db("replace_swap");
+        let mut db: OnDisk<u64, String> = OnDisk::open("replace_swap").unwrap();
+        for i in 0..3 {
+            db.put(i, "old".to_string()).unwrap();
+            db.sync().unwrap();
+        }
+        // a data file that can't be removed stops the swap after the marker
+        // is down and the new dataset renamed in, as a crash there would
+        let stuck = format!("replace_swap.{}.db", db.file_id + 1);
+        fs::create_dir_all(&stuck).unwrap();
+        assert!(db.replace_all(vec![(7, "new".to_string())]).is_err());
+        drop(db);
+        fs::remove_dir(&stuck).unwrap();
+        assert!(fs::exists(prune_marker_path("replace_swap")).unwrap());
+
+        let db: OnDisk<u64, String> = OnDisk::open("replace_swap").unwrap();
+        assert_eq!(db.keys_owned().unwrap(), vec![7]);
+        assert_eq!(db.get(&7).unwrap(), Some("new".to_string()));
+        assert_eq!(data_file_ids("replace_swap").unwrap(), vec![1]);
+        assert!(!fs::exists(prune_marker_path("replace_swap")).unwrap());
+    }
+
+    #[test]
+    fn import_checkpoint_follows_its_records_to_disk() {
+        let checkpoint = "import_durable.checkpoint";
+        remove_db("import_durable");
+        let mut db: OnDisk<u64, u64> = OnDisk::open("import_durable").unwrap();
+        db.set_write_coalescing(Some(100)).unwrap();
+
+        // keys repeat, so coalescing holds most of the writes back
+        let mut input = vec![];
+        for i in 0..1500u64 {
+            bincode::serialize_into(&mut input, &(i % 10, i)).unwrap();
+        }
+        let record_len = input.len() as u64 / 1500;
+        let interrupted = Interrupted {
+            inner: Cursor::new(input),
+            fail_at: record_len * 1200,
+        };
+        assert!(db.import_resumable(interrupted, checkpoint).is_err());
+        assert!(fs::exists(checkpoint).unwrap());
+
+        let on_disk: OnDisk<u64, u64> = OnDisk::open_read_only("import_durable").unwrap();
+        for key in 0..10 {
+            assert_eq!(on_disk.get(&key).unwrap(), Some(990 + key));
+        }
+        fs::remove_file(checkpoint).unwrap();
+    }
+
+    #[test]
+    fn deletes_after_writing_hints_survive_reopening_from_them() {
+        remove_db("hint_deletes");
+        let mut db: OnDisk<String, u64> = OnDisk::open("hint_deletes").unwrap();
+        db.put("a".to_string(), 1).unwrap();
+        db.put("b".to_string(), 2).unwrap();
+        db.put("c".to_string(), 3).unwrap();
+        db.delete(&"c".to_string()).unwrap();
+        db.prune().unwrap();
+        assert!(fs::exists("hint_deletes.1.hint.db").unwrap());
+        db.delete(&"a".to_string()).unwrap();
+        db.sync().unwrap();
+        // the tombstone went to a later file, so the hint still holds "a"
+        assert!(fs::exists("hint_deletes.1.hint.db").unwrap());
+        drop(db);
+
+        let mut db: OnDisk<String, u64> = OnDisk::open("hint_deletes").unwrap();
+        assert!(db.hinted_files.contains(&1));
+        assert_eq!(db.get(&"a".to_string()).unwrap(), None);
+        assert_eq!(db.get(&"b".to_string()).unwrap(), Some(2));
+        assert_eq!(db.get(&"c".to_string()).unwrap(), None);
+
+        // pruning again drops the tombstone along with the record it hid
+        db.prune().unwrap();
+        drop(db);
+        let db: OnDisk<String, u64> = OnDisk::open("hint_deletes").unwrap();
+        assert!(db.hinted_files.contains(&1));
+        assert_eq!(db.keys_owned().unwrap(), vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn running_byte_totals_follow_the_index() {
+        remove_db("byte_totals");
+        fn check(db: &OnDisk<u64, u64>) {
+            let live: u64 = db
+                .key_dir
+                .values()
+                .chain(db.spilled_entries().unwrap().iter().map(|(_, entry)| entry))
+                .map(|entry| entry.slot.end - entry.slot.start)
+                .sum();
+            let dead: u64 = db.free_slots.iter().map(|slot| slot.end - slot.start).sum();
+            assert_eq!(db.live_bytes, live);
+            assert_eq!(db.free_slots.total_len, dead);
+        }
+        let mut db: OnDisk<u64, u64> = OnDisk::open("byte_totals").unwrap();
+        db.set_hot_key_threshold(Some(3));
+        db.set_key_dir_limit(Some(8)).unwrap();
+        for i in 0..20 {
+            db.put(i, i).unwrap();
+        }
+        check(&db);
+        for i in 0..5 {
+            db.put(1, u64::MAX - i).unwrap();
+        }
+        // rewrites the file the hot key left dead records in
+        db.sync().unwrap();
+        check(&db);
+        db.delete(&2).unwrap();
+        db.put(30, 1 << 40).unwrap();
+        check(&db);
+        db.soft_delete(&3).unwrap();
+        check(&db);
+        db.restore(&3).unwrap();
+        let mut txn = db.begin();
+        txn.put(4, 44);
+        txn.delete(&5);
+        txn.commit().unwrap();
+        check(&db);
+        db.prune().unwrap();
+        check(&db);
+        db.delete(&6).unwrap();
+        drop(db);
+
+        let db: OnDisk<u64, u64> = OnDisk::open("byte_totals").unwrap();
+        check(&db);
+        assert!(db.live_bytes > 0);
     }
 }
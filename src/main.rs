@@ -1,15 +1,41 @@
 use anyhow::Result;
 use bitcask::{Db, OnDisk, ToDisk};
 use rustyline::{error::ReadlineError, DefaultEditor};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A value put from the shell, tagged with the type it was put as. The tag
+/// is stored with the value, so databases written when the shell stored bare
+/// strings can't be read by it anymore.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Value {
+    Str(String),
+    Int(i64),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Int(n) => write!(f, "{}", n),
+        }
+    }
+}
 
 fn main() -> Result<()> {
-    let mut db = OnDisk::open("test")?;
+    let mut db: OnDisk<String, Value> = OnDisk::open("test")?;
     let mut rl = DefaultEditor::new()?;
 
     rl.load_history("history.txt").unwrap_or(());
 
     loop {
-        let readline = rl.readline(">> ");
+        // a star marks writes a crash could still lose
+        let prompt = if db.has_unsynced_writes() {
+            "*>> "
+        } else {
+            ">> "
+        };
+        let readline = rl.readline(prompt);
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str())?;
@@ -18,26 +44,61 @@ fn main() -> Result<()> {
                     println!("exiting");
                     break;
                 } else if line.trim().starts_with("items") {
-                    let items = db.items();
+                    let items = db.items()?;
 
                     for item in items {
                         println!("{:?}", item);
                     }
                 } else if line.trim().starts_with("keys") {
-                    let keys = db.keys();
+                    let keys = db.keys()?;
 
                     for key in keys {
                         println!("{:?}", key);
                     }
                 } else if line.trim().starts_with("values") {
-                    let values = db.values();
+                    let values = db.values()?;
 
                     for value in values {
                         println!("{:?}", value);
                     }
+                } else if line.trim().starts_with("stats") {
+                    let stats = db.stats()?;
+                    println!("keys: {}", stats.live_keys);
+                    println!("tombstones: {}", stats.tombstones);
+                    println!("files: {}", stats.files);
+                    println!("bytes on disk: {}", stats.disk_bytes);
+                    println!(
+                        "reclaimable bytes: {} ({:.1}%)",
+                        stats.reclaimable_bytes,
+                        stats.dead_space_ratio() * 100.0
+                    );
+                } else if line.trim().starts_with("flush") {
+                    // `flush` fsyncs like the library's `sync` but keeps the
+                    // active file, so typing it often doesn't pile up files
+                    db.flush()?;
+                    println!("flushed");
                 } else if line.trim().starts_with("prune") {
                     println!("Pruning database");
                     db.prune()?;
+                } else if line.trim().starts_with("put:int") {
+                    let parsed_line = line.trim().strip_prefix("put:int").unwrap().to_string();
+                    let split: Vec<_> = parsed_line
+                        .split_ascii_whitespace()
+                        .map(|x| x.to_string())
+                        .collect();
+                    if split.len() != 2 {
+                        eprintln!("usage: put:int KEY VALUE");
+                        continue;
+                    }
+                    let key = split[0].clone();
+                    let value = match split[1].parse() {
+                        Ok(n) => Value::Int(n),
+                        Err(e) => {
+                            eprintln!("Invalid int {}: {}", split[1], e);
+                            continue;
+                        }
+                    };
+                    db.put(key, value)?;
                 } else if line.trim().starts_with("put") {
                     let parsed_line = line.trim().strip_prefix("put").unwrap().to_string();
                     let split: Vec<_> = parsed_line
@@ -45,7 +106,7 @@ fn main() -> Result<()> {
                         .map(|x| x.to_string())
                         .collect();
                     let key = split[0].clone();
-                    let value = split[1].clone();
+                    let value = Value::Str(split[1].clone());
                     db.put(key, value)?;
                 } else if line.trim().starts_with("get") {
                     let parsed_line = line.trim().strip_prefix("get").unwrap().to_string();
@@ -54,8 +115,10 @@ fn main() -> Result<()> {
                         .map(|x| x.to_string())
                         .collect();
                     let key = &split[0];
-                    let value = db.get(key).unwrap_or("None".to_string());
-                    println!("{}={}", key, value);
+                    match db.get(key)? {
+                        Some(value) => println!("{}={}", key, value),
+                        None => println!("{}=None", key),
+                    }
                 } else if line.trim().starts_with("delete") {
                     let parsed_line = line.trim().strip_prefix("delete").unwrap().to_string();
                     let split: Vec<_> = parsed_line
@@ -84,5 +147,5 @@ fn main() -> Result<()> {
         }
     }
     rl.save_history("history.txt")?;
-    Ok(())
+    db.close()
 }